@@ -58,8 +58,30 @@
 //! You dont need to duplicate the `<#` within code blocks and `#>` not in
 //! template text blocks.
 //!
+//! Longer parts of text can be put between `<#@ raw #>` and `<#@ endraw #>`
+//! instead, everything in between is printed exactly as written.
+//!
 //! You can use `<#= expr #>` to print out a single expression.
 //!
+//! A format spec can follow after a ` :`, e.g. `<#= self.price :>10.2 #>` or
+//! `<#= self.id :#x #>`. It accepts the same options as `format!` and is checked
+//! when the template is compiled. The colon needs a whitespace in front of it.
+//!
+//! `<#? expr #>` prints an expression with its `Debug` implementation and
+//! `<#? expr :# #>` pretty prints it.
+//!
+//! Comments are written within `<#--` and `--#>`, they are removed from the
+//! output and can contain `#>`.
+//!
+//! A `-` directly after the start or before the end of a block (`<#-`, `<#-=`,
+//! `-#>`, ...) removes all whitespace, including newlines, in front of or after
+//! the block:
+//! ```text
+//! <# for item in &self.items { -#>
+//!     <#= item #>,
+//! <#- } #>
+//! ```
+//!
 //! Maybe you noticed the magical `_fmt` in the template. This variable gives you
 //! access to the formatter and e.g. enables you to write functions in your
 //! template. `<# write!(_fmt, "{}", self.name)?; #>` is equal to `<#= self.name #>`.
@@ -69,6 +91,267 @@
 //!
 //! # Features
 //!
+//! ## Inline templates
+//!
+//! Small templates don't need their own file, use the `TemplateSource`
+//! attribute instead of `TemplatePath` to write the template directly:
+//! ```
+//! use t4rust_derive::Template;
+//!
+//! #[derive(Template)]
+//! #[TemplateSource = "Hello <#= self.name #>!"]
+//! struct Greeting {
+//!     name: String,
+//! }
+//!
+//! assert_eq!(Greeting { name: "Splamy".into() }.to_string(), "Hello Splamy!");
+//! ```
+//!
+//! Raw string literals (`r#"..."#`) work as well, which is handy for multi-line
+//! templates.
+//!
+//! ## Enums
+//!
+//! Enums can have a template for every variant. The fields of the variant can be
+//! used by their name, or as `_0`, `_1`, ... in tuple variants:
+//! ```
+//! use t4rust_derive::Template;
+//!
+//! #[derive(Template)]
+//! enum Shape {
+//!     #[TemplateSource = "Circle with radius <#= radius #>"]
+//!     Circle { radius: f32 },
+//!     #[TemplateSource = "Rectangle <#= _0 #>x<#= _1 #>"]
+//!     Rectangle(u32, u32),
+//! }
+//!
+//! assert_eq!(Shape::Rectangle(2, 3).to_string(), "Rectangle 2x3");
+//! ```
+//!
+//! The `TemplateDelimiters` and `TemplateDebug` attributes on the enum apply to
+//! all variants. An enum with a `TemplatePath` or `TemplateSource` on itself uses
+//! that one template for all variants instead.
+//!
+//! ## Using fields directly
+//!
+//! With the `TemplateBindFields` attribute the fields of a struct are available
+//! as variables, so `<#= name #>` can be written instead of `<#= self.name #>`:
+//! ```
+//! use t4rust_derive::Template;
+//!
+//! #[derive(Template)]
+//! #[TemplateSource = "<#= name #> is <#= age #> years old"]
+//! #[TemplateBindFields]
+//! struct Person {
+//!     name: String,
+//!     age: u32,
+//! }
+//!
+//! assert_eq!(Person { name: "Splamy".into(), age: 5 }.to_string(), "Splamy is 5 years old");
+//! ```
+//!
+//! Fields of tuple structs are called `_0`, `_1`, ... and the variables are
+//! references to the fields. A field called `_fmt` can not be used with this
+//! attribute.
+//!
+//! ## Named templates
+//!
+//! A type can have more templates with the `TemplateNamed` attribute, e.g. to
+//! render it as text and as html. Every named template gets a `render_<name>`
+//! method which returns a `String` and a `render_<name>_into` method which
+//! writes into any `fmt::Write`:
+//! ```
+//! use t4rust::Template;
+//!
+//! #[derive(Template)]
+//! #[TemplateSource = "<#= self.name #>"]
+//! #[TemplateNamed(name = "html", source = "<#@ escape mode=\"html\" #><b><#= self.name #></b>")]
+//! struct User {
+//!     name: String,
+//! }
+//!
+//! let user = User { name: "<Splamy>".into() };
+//! assert_eq!(user.to_string(), "<Splamy>");
+//! assert_eq!(user.render_html(), "<b>&lt;Splamy&gt;</b>");
+//! ```
+//!
+//! Use `path` instead of `source` for a template file. The name can contain
+//! lowercase ASCII letters, digits and `_`. `Display` is only implemented if the
+//! type has a `TemplatePath` or `TemplateSource` as well.
+//!
+//! ## Including other templates
+//!
+//! Use the `include` directive to insert another template file:
+//! ```text
+//! <#@ include file="header.tt" #>
+//! ```
+//!
+//! The path is relative to the including file (or to the crate root for inline
+//! templates). The included file is parsed as if its content was written in place
+//! of the directive, so it can use code blocks, expressions and directives itself.
+//! Changing an included file will also trigger a rebuild.
+//!
+//! ## Template inheritance
+//!
+//! A base template can mark parts as blocks, which other templates can replace:
+//! ```text
+//! <html>
+//! <head><title><#@ block name="title" #>Default title<#@ endblock #></title></head>
+//! <body><#@ block name="body" #><#@ endblock #></body>
+//! </html>
+//! ```
+//!
+//! A template which starts with `<#@ extends file="base.tt" #>` uses the base
+//! template and only contains the blocks it wants to replace:
+//! ```text
+//! <#@ extends file="base.tt" #>
+//! <#@ block name="title" #><#= self.title #><#@ endblock #>
+//! <#@ block name="body" #>
+//! <h1><#= self.title #></h1>
+//! <#@ endblock #>
+//! ```
+//!
+//! Blocks can be nested and the base template can extend another template itself.
+//! When a block is replaced, the blocks nested inside of it are gone, so replacing
+//! them as well has no effect. Outside of blocks, the extending template may only
+//! contain directives, functions, features and whitespace. The path is resolved
+//! like for `include`.
+//!
+//! ## Template functions
+//!
+//! Parts of a template which are needed more than once can be written as a
+//! function with the `function` directive:
+//! ```text
+//! <#@ function name="item" params="name: &str, count: u32" #>
+//! <li><#= name #> (<#= count #>)</li>
+//! <#@ endfunction #>
+//! ```
+//!
+//! This adds a method to your type which returns an `impl Display`, so it can be
+//! called from any expression in the template, also before the definition:
+//! ```text
+//! <#! self.item("Apples", 3) #>
+//! ```
+//!
+//! Use `<#! #>` to call functions, otherwise the output of the function is escaped
+//! a second time. The parameters may only borrow for as long as `self`, elided
+//! lifetimes like in `&str` are extended accordingly. Parameters which are not
+//! references or primitive types are references in the body, because a function can
+//! be rendered more than once.
+//!
+//! All templates of a type share their functions, so they can include the same file
+//! with functions. Functions with the same name but another definition or other
+//! imports are an error.
+//!
+//! ## Feature blocks
+//!
+//! Like class feature blocks in T4, `<#+ #>` blocks contain items instead of code
+//! which runs while rendering:
+//! ```text
+//! <#+
+//! const SEPARATOR: &'static str = ", ";
+//!
+//! fn names(&self) -> String {
+//!     self.people.join(Self::SEPARATOR)
+//! }
+//!
+//! struct Initial<'a>(&'a str);
+//! #>
+//! Names: <#= self.names() #>
+//! ```
+//!
+//! Functions and constants are added to your type, so they are called with
+//! `self.names()` or `Self::SEPARATOR`. All other items, e.g. structs and their
+//! impls, can only be used within the template.
+//!
+//! Like functions, feature blocks of a file which several templates of a type
+//! include are only added once.
+//!
+//! ## Imports
+//!
+//! Instead of writing full paths or `use` in code blocks, the `import` directive
+//! adds a `use` item for the whole template:
+//! ```text
+//! <#@ import namespace="std::collections::BTreeMap" #>
+//! <#@ import namespace="crate::helpers::{shout, double}" #>
+//! ```
+//!
+//! The namespace is everything which can follow after `use`. Paths are resolved
+//! from the module of your type, just like in a `use` item there. The imports are
+//! only visible to the code and functions of this template, items in `<#+ #>`
+//! blocks need their own `use`.
+//!
+//! ## Declared parameters
+//!
+//! A template can declare which fields it expects with the `parameter` directive,
+//! like in T4:
+//! ```text
+//! <#@ parameter name="title" type="String" #>
+//! <#@ parameter name="tags" #>
+//! ```
+//!
+//! The derive then checks that your struct (or enum variant) has these fields and
+//! lists all which are missing or have another type. The `type` is optional and
+//! compared as written, so `String` and `std::string::String` are different. Tuple
+//! fields are called `0`, `1`, ... or `_0`, `_1`, ...
+//!
+//! For example, this fails with
+//! ```text
+//! The template expects fields which `Post` does not have:
+//! - `title` has the type `&'static str` instead of `String`
+//! - `tags` is missing
+//! ```
+//! ```rust,compile_fail
+//! use t4rust_derive::Template;
+//!
+//! #[derive(Template)]
+//! #[TemplateSource = r#"<#@ parameter name="title" type="String" #>
+//! <#@ parameter name="tags" #><#= self.title #>"#]
+//! struct Post {
+//!     title: &'static str,
+//! }
+//! ```
+//!
+//! ## Rendering into writers
+//!
+//! Add the `t4rust` crate to your dependencies and derive `t4rust::Template`
+//! instead of `t4rust_derive::Template`. This also implements the `t4rust::Template`
+//! trait, which renders the template directly into any `fmt::Write` or
+//! `io::Write`, without building a `String` first:
+//! ```rust,ignore
+//! use t4rust::Template;
+//!
+//! let mut file = std::fs::File::create("out.txt")?;
+//! example.write_into(&mut file)?;
+//!
+//! let mut text = String::new();
+//! example.render_into(&mut text)?;
+//!
+//! let text = example.render();
+//! ```
+//!
+//! `render()` preallocates the `String` with `Template::SIZE_HINT`, the length
+//! of all static text in the template.
+//!
+//! ## Custom delimiters
+//!
+//! If the generated text contains a lot of `<#` and `#>`, e.g. for other T4-like
+//! languages, you can change the delimiters with the `TemplateDelimiters`
+//! attribute. All blocks then use the new delimiters, like `{%= expr %}` or
+//! `{%@ template cleanws="true" %}`:
+//! ```rust
+//! use t4rust_derive::Template;
+//!
+//! #[derive(Template)]
+//! #[TemplateSource = "<#= T4 #> {%= self.name %}"]
+//! #[TemplateDelimiters = "{% %}"]
+//! struct Example {
+//!     name: String,
+//! }
+//!
+//! assert_eq!(Example { name: "Splamy".into() }.to_string(), "<#= T4 #> Splamy");
+//! ```
+//!
 //! ## Auto-escaping
 //!
 //! Use the `escape` directive in your .tt file:
@@ -83,11 +366,70 @@
 //! }
 //! ```
 //!
-//! All expression blocks (e.g. `<#= self.name #>`) will call the escape
-//! function before inserted.
+//! All expression blocks (e.g. `<#= self.name #>` or `<#? self.items #>`) will
+//! call the escape function before inserted.
 //!
 //! You can redeclare this directive as many times and where you want in your
 //! template to change or disable (with `function=""`) the escape function.
+//!
+//! When deriving `t4rust::Template` you can also use one of the built-in escape
+//! functions by name instead:
+//! ```text
+//! <#@ escape mode="html" #>
+//! ```
+//!
+//! Available modes are `html`, `xml`, `json`, `url`, `shell`, `csv` and `rust`.
+//! The modes for quoted strings (`json`, `shell`, `csv` and `rust`) only escape
+//! the content, so the quotes have to be written in the template, e.g.
+//! `"<#= self.name #>"`.
+//!
+//! The escape function above allocates a new `String` for every expression. When
+//! deriving `t4rust::Template` you can instead use an escape function which writes
+//! into the output as the value is formatted:
+//! ```rust
+//! use std::fmt;
+//!
+//! fn escape_html(out: &mut dyn fmt::Write, s: &str) -> fmt::Result {
+//!     todo!(); /* Your escaping code here */
+//! }
+//! ```
+//! ```text
+//! <#@ escape writer="escape_html" #>
+//! ```
+//!
+//! The built-in modes work this way too.
+//!
+//! To print an expression without escaping, e.g. html which is already escaped,
+//! use `<#! expr #>` instead of `<#= expr #>`.
+//!
+//! ## Directive checks
+//!
+//! Unknown directives, unknown parameters and invalid values (like
+//! `cleanws="yes"`) are compile errors. If you'd rather have warnings in the
+//! build output, enable the lenient mode:
+//! ```text
+//! <#@ template lenient="true" #>
+//! ```
+//!
+//! For example, this fails with `Unknown directive "tempalte", did you mean
+//! "template"?`:
+//! ```rust,compile_fail
+//! use t4rust_derive::Template;
+//!
+//! #[derive(Template)]
+//! #[TemplateSource = "<#@ tempalte cleanws=\"true\" #>Hello"]
+//! struct Typo;
+//! ```
+//!
+//! ## Errors in template code
+//!
+//! Rust errors in the code of a template file point to the generated code in
+//! `target/t4rust`. There every line of template code starts with a comment which
+//! shows where it is in the template, e.g. `/* templates/page.tt:12:5 */`, so the
+//! error message contains the template line as well. Errors in inline templates
+//! point to the `TemplateSource` attribute.
+
+#![allow(clippy::needless_doctest_main)]
 
 extern crate proc_macro;
 
@@ -112,7 +454,10 @@ use nom::{
 	sequence::tuple,
 	IResult,
 };
+use proc_macro2::{Delimiter, Group, Spacing, TokenTree};
 use quote::quote;
+use syn::spanned::Spanned;
+use syn::visit_mut::VisitMut;
 use syn::Meta::*;
 use syn::*;
 
@@ -131,90 +476,972 @@ macro_rules! dbg_print {
 }
 
 const TEMPLATE_PATH_MACRO: &str = "TemplatePath";
+const TEMPLATE_SOURCE_MACRO: &str = "TemplateSource";
 const TEMPLATE_DEBUG_MACRO: &str = "TemplateDebug";
-
-#[proc_macro_derive(Template, attributes(TemplatePath, TemplateDebug))]
+const TEMPLATE_DELIMITERS_MACRO: &str = "TemplateDelimiters";
+const TEMPLATE_BIND_FIELDS_MACRO: &str = "TemplateBindFields";
+const TEMPLATE_NAMED_MACRO: &str = "TemplateNamed";
+
+const USER_CODE_START: &str = "__t4rust_user_code_start";
+const USER_CODE_END: &str = "__t4rust_user_code_end";
+
+#[proc_macro_derive(
+	Template,
+	attributes(
+		TemplatePath,
+		TemplateSource,
+		TemplateDebug,
+		TemplateDelimiters,
+		TemplateBindFields,
+		TemplateNamed
+	)
+)]
 pub fn transform_template(
 	input: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
 	let macro_input = parse_macro_input!(input as DeriveInput);
+	match derive_template(&macro_input, false) {
+		Ok(tokens) => tokens.into(),
+		Err(err) => err.into_compile_error().into(),
+	}
+}
 
-	let mut path: Option<String> = None;
+/// The `Template` derive of the `t4rust` crate, which re-exports it under that
+/// name. It additionally implements the `t4rust::Template` trait and allows
+/// the escape functions of `t4rust`.
+#[proc_macro_derive(
+	RuntimeTemplate,
+	attributes(
+		TemplatePath,
+		TemplateSource,
+		TemplateDebug,
+		TemplateDelimiters,
+		TemplateBindFields,
+		TemplateNamed
+	)
+)]
+pub fn transform_runtime_template(
+	input: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+	let macro_input = parse_macro_input!(input as DeriveInput);
+	match derive_template(&macro_input, true) {
+		Ok(tokens) => tokens.into(),
+		Err(err) => err.into_compile_error().into(),
+	}
+}
+
+/// `runtime` is set when the derive is used through the `t4rust` crate.
+fn derive_template(
+	macro_input: &DeriveInput,
+	runtime: bool,
+) -> Result<proc_macro2::TokenStream, syn::Error>
+{
 	let mut info = TemplateInfo::default();
+	info.runtime = runtime;
+	let attributes = parse_attributes(&macro_input.attrs, &mut info)?;
+	let name = &macro_input.ident;
+
+	let bind_fields = match (&macro_input.data, attributes.bind_fields) {
+		(_, None) => None,
+		(Data::Struct(data), Some(_)) => {
+			Some(fields_pattern(quote! { Self }, &data.fields)?)
+		}
+		(_, Some(span)) => {
+			return Err(syn::Error::new(
+				span,
+				format!(
+					"#[{}] only works on structs, the fields of enum variants \
+					 are always available",
+					TEMPLATE_BIND_FIELDS_MACRO
+				),
+			));
+		}
+	};
+	let bind = |body: proc_macro2::TokenStream| match &bind_fields {
+		None => body,
+		Some(pattern) => quote! {
+			#[allow(unused_variables)]
+			let #pattern = self;
+			#body
+		},
+	};
+
+	let mut variants = Vec::new();
+	if let Data::Enum(data) = &macro_input.data {
+		for variant in &data.variants {
+			let mut variant_info = info.inherit();
+			let variant_attributes =
+				parse_attributes(&variant.attrs, &mut variant_info)?;
+			if !variant_attributes.named.is_empty()
+				|| variant_attributes.bind_fields.is_some()
+			{
+				return Err(syn::Error::new_spanned(
+					&variant.ident,
+					format!(
+						"#[{}] and #[{}] can only be used on the enum itself",
+						TEMPLATE_NAMED_MACRO, TEMPLATE_BIND_FIELDS_MACRO
+					),
+				));
+			}
+			if variant_attributes.source.is_some()
+				&& attributes.source.is_some()
+			{
+				return Err(syn::Error::new_spanned(
+					&variant.ident,
+					"The enum already has a template, either use one template \
+					 for the enum or one for every variant",
+				));
+			}
+			variants.push((variant, variant_attributes.source, variant_info));
+		}
+	}
+	// Enums without a template of their own get one template per variant,
+	// unless they only have named templates.
+	let per_variant = matches!(macro_input.data, Data::Enum(_))
+		&& attributes.source.is_none()
+		&& (attributes.named.is_empty()
+			|| variants.iter().any(|(_, source, _)| source.is_some()));
+
+	// All compiled templates, for the debug output
+	let mut templates = Vec::new();
+	// Fields which templates of the whole type can declare as parameters
+	let fields = match &macro_input.data {
+		Data::Struct(data) => Some((name, &data.fields)),
+		_ => None,
+	};
 
-	for attr in &macro_input.attrs {
+	// Code of `Display::fmt` and the size hint, if there is a default template
+	let display = if let Some(source) = &attributes.source {
+		let description = format!("the template of `{}`", name);
+		let template = compile_template(source, info.inherit(), description)?;
+		check_parameters(&template, fields, name)?;
+		let display = (bind(template.body()), template.size_hint);
+		templates.push(template);
+		Some(display)
+	} else if per_variant {
+		let mut arms = Vec::new();
+		// Preallocate enough for the largest variant
+		let mut size_hint = 0;
+		for (variant, source, info) in variants {
+			let source =
+				source.ok_or_else(|| missing_source_error(&variant.ident))?;
+			let description =
+				format!("the template of `{}::{}`", name, variant.ident);
+			let template = compile_template(&source, info, description)?;
+			check_parameters(
+				&template,
+				Some((&variant.ident, &variant.fields)),
+				name,
+			)?;
+			let pattern = variant_pattern(variant)?;
+			let CompiledTemplate { include, tokens, .. } = &template;
+			arms.push(quote! {
+				#[allow(unused_variables)]
+				#pattern => {
+					#(#include)*
+					#tokens
+				}
+			});
+			size_hint = size_hint.max(template.size_hint);
+			templates.push(template);
+		}
+		let body = if arms.is_empty() {
+			quote! { match *self {} }
+		} else {
+			quote! {
+				match self { #(#arms)* }
+				Ok(())
+			}
+		};
+		Some((body, size_hint))
+	} else if attributes.named.is_empty() {
+		return Err(missing_source_error(name));
+	} else {
+		None
+	};
+
+	// Build frame and insert
+	let (impl_generics, ty_generics, where_clause) =
+		macro_input.generics.split_for_impl();
+
+	let display_impl = if let Some((body, size_hint)) = display {
+		let runtime_impl = if runtime {
+			quote! {
+				impl #impl_generics ::t4rust::Template for #name #ty_generics #where_clause {
+					const SIZE_HINT: usize = #size_hint;
+				}
+			}
+		} else {
+			quote! {}
+		};
+
+		quote! {
+			impl #impl_generics ::std::fmt::Display for #name #ty_generics #where_clause {
+				fn fmt(&self, _fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+					#body
+				}
+			}
+
+			#runtime_impl
+		}
+	} else {
+		quote! {}
+	};
+
+	// Every named template gets a `fmt` like function, which is called through
+	// a `Display` adapter to render into any writer.
+	let mut methods = Vec::new();
+	for (template_name, source) in &attributes.named {
+		let description = format!("the `{}` template", template_name);
+		let template = compile_template(source, info.inherit(), description)?;
+		check_parameters(&template, fields, name)?;
+		let body = bind(template.body());
+		let size_hint = template.size_hint;
+		templates.push(template);
+
+		let ident = |format: &str| {
+			Ident::new(&format.replace("{}", template_name), name.span())
+		};
+		let fmt_fn = ident("__t4rust_fmt_{}");
+		let render_into = ident("render_{}_into");
+		let render = ident("render_{}");
+		let doc_render_into =
+			format!(" Renders the `{}` template into a writer.", template_name);
+		let doc_render =
+			format!(" Renders the `{}` template into a `String`.", template_name);
+		methods.push(quote! {
+			fn #fmt_fn(&self, _fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+				#body
+			}
+
+			#[doc = #doc_render_into]
+			pub fn #render_into<W: ::std::fmt::Write + ?Sized>(&self, writer: &mut W) -> ::std::fmt::Result {
+				write!(writer, "{}", __T4rustView(self, Self::#fmt_fn))
+			}
+
+			#[doc = #doc_render]
+			pub fn #render(&self) -> String {
+				let mut s = String::with_capacity(#size_hint);
+				self.#render_into(&mut s)
+					.expect("a template returned an error unexpectedly");
+				s
+			}
+		});
+	}
+	// Functions defined in the templates return a `Display` adapter around a
+	// closure, which writes the body of the function.
+	let has_functions = templates.iter().any(|t| !t.functions.is_empty());
+	// Templates can include the same file with functions, identical
+	// definitions are only added once.
+	let mut defined: Vec<(&TemplateFunction, &CompiledTemplate)> = Vec::new();
+	for template in &templates {
+		for function in &template.functions {
+			match defined.iter().find(|(f, _)| f.name == function.name) {
+				Some((f, _)) if f.code == function.code => continue,
+				Some((_, other)) => {
+					return Err(syn::Error::new(
+						template.span,
+						format!(
+							"The function `{}` is defined differently in {} and \
+							 {}, functions with the same name need the same code \
+							 and imports",
+							function.name, other.description, template.description
+						),
+					));
+				}
+				None => defined.push((function, template)),
+			}
+		}
+	}
+	for (TemplateFunction { signature, body, .. }, _) in &defined {
+		let body = bind(quote! {
+			#body
+			Ok(())
+		});
+		methods.push(quote! {
+			fn #signature -> impl ::std::fmt::Display + 't4 {
+				__T4rustFn(move |_fmt: &mut ::std::fmt::Formatter| -> ::std::fmt::Result {
+					#body
+				})
+			}
+		});
+	}
+
+	let view_struct = if attributes.named.is_empty() {
+		quote! {}
+	} else {
+		quote! {
+			struct __T4rustView<'a, T: ?Sized>(
+				&'a T,
+				fn(&T, &mut ::std::fmt::Formatter) -> ::std::fmt::Result,
+			);
+
+			impl<'a, T: ?Sized> ::std::fmt::Display for __T4rustView<'a, T> {
+				fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+					(self.1)(self.0, f)
+				}
+			}
+		}
+	};
+	let fn_struct = if has_functions {
+		quote! {
+			struct __T4rustFn<F>(F);
+
+			impl<F> ::std::fmt::Display for __T4rustFn<F>
+			where F: Fn(&mut ::std::fmt::Formatter) -> ::std::fmt::Result
+			{
+				fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+					(self.0)(f)
+				}
+			}
+		}
+	} else {
+		quote! {}
+	};
+
+	// Feature blocks of a file which several templates include are only added
+	// once. Other items with the same name are reported here, rustc would
+	// point to the derive.
+	let mut features: Vec<&FeatureBlock> = Vec::new();
+	// Name, if it is part of the impl and the template which defines it
+	let mut names: Vec<(&str, bool, &CompiledTemplate)> =
+		defined.iter().map(|(f, t)| (f.name.as_str(), true, *t)).collect();
+	for template in &templates {
+		for block in &template.features {
+			if features.iter().any(|b| b.code == block.code) {
+				continue;
+			}
+			for (name, in_impl) in &block.names {
+				if let Some((_, _, other)) =
+					names.iter().find(|(n, i, _)| n == name && i == in_impl)
+				{
+					return Err(syn::Error::new(
+						template.span,
+						format!(
+							"`{}` is defined in {} and again in a `<#+ #>` block \
+							 of {}",
+							name, other.description, template.description
+						),
+					));
+				}
+				names.push((name, *in_impl, template));
+			}
+			features.push(block);
+		}
+	}
+	methods.extend(features.iter().flat_map(|b| b.impl_items.clone()));
+	let items = features.iter().flat_map(|b| &b.items).collect::<Vec<_>>();
+
+	let methods_impl = if methods.is_empty() {
+		quote! {}
+	} else {
+		quote! {
+			impl #impl_generics #name #ty_generics #where_clause {
+				#(#methods)*
+			}
+		}
+	};
+	// Helpers are hidden in an unnamed constant, which the generated code
+	// needs to be in to use them.
+	let frame = if methods.is_empty() && items.is_empty() {
+		display_impl
+	} else {
+		quote! {
+			const _: () = {
+				#(#items)*
+				#view_struct
+				#fn_struct
+
+				#display_impl
+				#methods_impl
+			};
+		}
+	};
+
+	// We could return the code now. The problem is that span information are
+	// missing and the error messages are awful.
+	// So instead, we write to a file and include! this file, which still does
+	// not give us nice errors but at least includes source code.
+	if !templates.iter().any(|t| t.debug_print) {
+		Ok(frame)
+	} else {
+		let error = |msg: String| syn::Error::new_spanned(name, msg);
+		// Use hash of the template paths (or the inline templates) as filename
+		let mut hasher = DefaultHasher::new();
+		hasher.write(name.to_string().as_bytes());
+		for template in &templates {
+			hasher.write(template.hash_key.as_bytes());
+		}
+
+		let out_dir = generated_dir().map_err(error)?;
+		let code_path =
+			out_dir.join(hasher.finish().to_string()).with_extension("rs");
+
+		let write_error = |e: std::io::Error| {
+			error(format!(
+				"Failed to write compiled template to \"{}\": {}",
+				code_path.display(),
+				e
+			))
+		};
+		std::fs::create_dir_all(&out_dir).map_err(write_error)?;
+
+		let mut code = frame.to_string();
+		for (placeholder, template_code) in
+			templates.iter().flat_map(|t| &t.debug_code)
+		{
+			code = code.replace(placeholder, template_code);
+		}
+
+		// Write file
+		std::fs::write(&code_path, code.as_bytes()).map_err(write_error)?;
+
+		let code_path_str = path_to_str(&code_path).map_err(error)?;
+		Ok(quote! { include!(#code_path_str); })
+	}
+}
+
+/// Reads the template attributes of the type or of an enum variant.
+fn parse_attributes(
+	attrs: &[Attribute],
+	info: &mut TemplateInfo,
+) -> Result<TemplateAttributes, syn::Error>
+{
+	let mut result = TemplateAttributes {
+		source: None,
+		named: Vec::new(),
+		bind_fields: None,
+	};
+	for attr in attrs {
 		match &attr.meta {
 			NameValue(MetaNameValue {
 				path: p,
-				value: syn::Expr::Lit(ExprLit {attrs: _, lit: Lit::Str(lit_str)}),
+				value: syn::Expr::Lit(ExprLit { attrs: _, lit: Lit::Str(lit_str) }),
 				..
-			}) => {
-				if p.get_ident().expect("Attribute with no name")
-					== TEMPLATE_PATH_MACRO
-				{
-					path = Some(lit_str.value());
+			}) if p.is_ident(TEMPLATE_PATH_MACRO)
+				|| p.is_ident(TEMPLATE_SOURCE_MACRO) =>
+			{
+				if result.source.is_some() {
+					return Err(syn::Error::new_spanned(
+						attr,
+						format!(
+							"Only one #[{}] or #[{}] attribute is allowed",
+							TEMPLATE_PATH_MACRO, TEMPLATE_SOURCE_MACRO
+						),
+					));
 				}
+				result.source = Some(if p.is_ident(TEMPLATE_PATH_MACRO) {
+					TemplateSource::Path(lit_str.clone())
+				} else {
+					TemplateSource::Inline(lit_str.clone())
+				});
 			}
-			Path(name) => {
-				if name.get_ident().expect("Attribute with no name")
-					== TEMPLATE_DEBUG_MACRO
-				{
-					info.debug_print = true;
+			Path(name) if name.is_ident(TEMPLATE_DEBUG_MACRO) => {
+				info.debug_print = true;
+			}
+			Path(name) if name.is_ident(TEMPLATE_BIND_FIELDS_MACRO) => {
+				result.bind_fields = Some(name.span());
+			}
+			NameValue(MetaNameValue {
+				path: p,
+				value: syn::Expr::Lit(ExprLit { attrs: _, lit: Lit::Str(lit_str) }),
+				..
+			}) if p.is_ident(TEMPLATE_DELIMITERS_MACRO) => {
+				info.delimiters =
+					Delimiters::parse(&lit_str.value()).ok_or_else(|| {
+						syn::Error::new_spanned(
+							lit_str,
+							"Expected the start and end delimiter separated \
+							 by a space, e.g. \"{% %}\"",
+						)
+					})?;
+			}
+			List(list) if list.path.is_ident(TEMPLATE_NAMED_MACRO) => {
+				let (name, source) = parse_named_template(attr)?;
+				if result.named.iter().any(|(n, _)| *n == name) {
+					return Err(syn::Error::new_spanned(
+						attr,
+						format!("There is already a template called `{}`", name),
+					));
+				}
+				// `render_{name}_into` of one template would be `render_{name}`
+				// of the other
+				if let Some((other, _)) = result.named.iter().find(|(n, _)| {
+					name == format!("{}_into", n) || *n == format!("{}_into", name)
+				}) {
+					return Err(syn::Error::new_spanned(
+						attr,
+						format!(
+							"The templates `{}` and `{}` would both have a \
+							 `render_{}` method, choose another name",
+							other,
+							name,
+							if name.len() > other.len() { &name } else { other }
+						),
+					));
 				}
+				result.named.push((name, source));
 			}
 			_ => {}
 		}
 	}
+	Ok(result)
+}
+
+/// Reads `#[TemplateNamed(name = "...", path = "...")]`, `source` can be used
+/// instead of `path`.
+fn parse_named_template(
+	attr: &Attribute,
+) -> Result<(String, TemplateSource), syn::Error>
+{
+	let mut name = None;
+	let mut source = None;
+	attr.parse_nested_meta(|meta| {
+		let value: LitStr = meta.value()?.parse()?;
+		if meta.path.is_ident("name") {
+			// The name becomes part of snake case method names
+			let name_value = value.value();
+			let valid = name_value.starts_with(|c: char| c.is_ascii_lowercase())
+				&& name_value.chars().all(|c| {
+					c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'
+				});
+			if !valid {
+				return Err(syn::Error::new_spanned(
+					&value,
+					"The name has to be lowercase snake case, e.g. \"html\" or \
+					 \"list_item\"",
+				));
+			}
+			name = Some(name_value);
+		} else if meta.path.is_ident("path") || meta.path.is_ident("source") {
+			if source.is_some() {
+				return Err(meta.error("Only one `path` or `source` is allowed"));
+			}
+			source = Some(if meta.path.is_ident("path") {
+				TemplateSource::Path(value)
+			} else {
+				TemplateSource::Inline(value)
+			});
+		} else {
+			return Err(meta.error("Expected `name`, `path` or `source`"));
+		}
+		Ok(())
+	})?;
+	match (name, source) {
+		(Some(name), Some(source)) => Ok((name, source)),
+		_ => Err(syn::Error::new_spanned(
+			attr,
+			format!(
+				"Expected a name and a template, e.g. #[{}(name = \"html\", \
+				 path = \"<path>\")]",
+				TEMPLATE_NAMED_MACRO
+			),
+		)),
+	}
+}
 
-	// Get template path
-	let mut path_absolute =
-		PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
-	path_absolute.push(&path.unwrap_or_else(|| {
-		panic!(
-			"Please specify a #[{}=\"<path>\"] atribute with the template \
-			 file path.",
-			TEMPLATE_PATH_MACRO
+/// Compares the `parameter` directives of a template with the fields of the
+/// struct or enum variant it belongs to.
+fn check_parameters(
+	template: &CompiledTemplate,
+	fields: Option<(&Ident, &Fields)>,
+	type_name: &Ident,
+) -> Result<(), syn::Error>
+{
+	if template.parameters.is_empty() {
+		return Ok(());
+	}
+	let (ident, fields) = fields.ok_or_else(|| {
+		syn::Error::new_spanned(
+			type_name,
+			"The parameter directive can only be used in templates of structs \
+			 and enum variants",
 		)
-	}));
-	let path =
-		&path_absolute.canonicalize().expect("Could not canonicalize path");
-	dbg_println!(
-		info,
-		"Looking for template in \"{}\"",
-		path.to_str().unwrap()
-	);
+	})?;
+
+	let mut problems = Vec::new();
+	for (name, ty) in &template.parameters {
+		// Tuple fields can be called `0` or `_0`
+		let field = fields.iter().enumerate().find(|(i, field)| {
+			match &field.ident {
+				Some(field_name) => field_name == name,
+				None => *name == i.to_string() || *name == format!("_{}", i),
+			}
+		});
+		let expected = syn::parse_str::<Type>(ty)
+			.ok()
+			.map(|ty| type_to_string(quote! { #ty }));
+		match (field, expected) {
+			(None, Some(expected)) => problems
+				.push(format!("`{}: {}` is missing", name, expected)),
+			(None, None) => problems.push(format!("`{}` is missing", name)),
+			(Some((_, field)), Some(expected)) => {
+				let actual = &field.ty;
+				let actual = type_to_string(quote! { #actual });
+				if actual != expected {
+					problems.push(format!(
+						"`{}` has the type `{}` instead of `{}`",
+						name, actual, expected
+					));
+				}
+			}
+			(Some(_), None) => {}
+		}
+	}
 
-	// Read template file
-	let read = read_from_file(path).expect("Could not read file");
+	if problems.is_empty() {
+		return Ok(());
+	}
+	Err(syn::Error::new_spanned(
+		ident,
+		format!(
+			"The template expects fields which `{}` does not have:\n{}",
+			ident,
+			problems
+				.iter()
+				.map(|p| format!("- {}", p))
+				.collect::<Vec<_>>()
+				.join("\n")
+		),
+	))
+}
 
-	// Parse template file
-	let mut data = match parse_all(&mut info, &read) {
-		Ok(data) => data,
-		Err(e) => {
-			return syn::Error::new_spanned(macro_input, format!("Parse error: {}, reason: {}", e.index, e.reason))
-				.into_compile_error()
-				.into()
+/// Formats a type like it is usually written, e.g. `&'static str` instead of
+/// the `& 'static str` of `TokenStream::to_string`.
+fn type_to_string(tokens: proc_macro2::TokenStream) -> String {
+	let mut result = String::new();
+	let mut last_word = false;
+	// Groups are separated from keywords, like `*const [T]`, but not `fn(u8)`
+	let mut last_keyword = false;
+	let mut space_after = false;
+	for token in tokens {
+		let word = matches!(token, TokenTree::Ident(_) | TokenTree::Literal(_));
+		let group = matches!(token, TokenTree::Group(_));
+		if space_after || (word && last_word) || (group && last_keyword) {
+			result.push(' ');
 		}
+		last_word = word;
+		last_keyword = matches!(&token, TokenTree::Ident(ident)
+			if ["const", "mut", "dyn", "impl"].iter().any(|k| ident == k));
+		space_after = false;
+		match token {
+			TokenTree::Group(group) => {
+				let (open, close) = match group.delimiter() {
+					Delimiter::Parenthesis => ("(", ")"),
+					Delimiter::Bracket => ("[", "]"),
+					Delimiter::Brace => ("{ ", " }"),
+					Delimiter::None => ("", ""),
+				};
+				result.push_str(open);
+				result.push_str(&type_to_string(group.stream()));
+				result.push_str(close);
+			}
+			TokenTree::Punct(punct) => {
+				let c = punct.as_char();
+				match c {
+					',' | ';' => space_after = true,
+					// Binary operators like in `dyn Trait + Send` or `->`
+					'+' | '=' => {
+						result.push(' ');
+						space_after = true;
+					}
+					'-' if punct.spacing() == Spacing::Joint => {
+						result.push(' ')
+					}
+					'>' if result.ends_with('-') => space_after = true,
+					_ => {}
+				}
+				result.push(c);
+			}
+			other => result.push_str(&other.to_string()),
+		}
+	}
+	result
+}
+
+fn missing_source_error(ident: &Ident) -> syn::Error {
+	syn::Error::new_spanned(
+		ident,
+		format!(
+			"Please specify a #[{}=\"<path>\"] atribute with the template file \
+			 path or a #[{}=\"<template>\"] attribute with the template itself.",
+			TEMPLATE_PATH_MACRO, TEMPLATE_SOURCE_MACRO
+		),
+	)
+}
+
+/// Pattern which matches the variant and binds its fields.
+fn variant_pattern(
+	variant: &Variant,
+) -> Result<proc_macro2::TokenStream, syn::Error>
+{
+	let ident = &variant.ident;
+	fields_pattern(quote! { Self::#ident }, &variant.fields)
+}
+
+/// Pattern which destructures `path` and binds all fields by their name, or as
+/// `_0`, `_1`, ... for tuple fields.
+fn fields_pattern(
+	path: proc_macro2::TokenStream,
+	fields: &Fields,
+) -> Result<proc_macro2::TokenStream, syn::Error>
+{
+	Ok(match fields {
+		Fields::Named(fields) => {
+			let names = fields
+				.named
+				.iter()
+				.map(|field| {
+					let name = field.ident.as_ref().unwrap();
+					if name == "_fmt" {
+						return Err(syn::Error::new_spanned(
+							name,
+							"A field called `_fmt` would hide the formatter \
+							 in the template",
+						));
+					}
+					Ok(name)
+				})
+				.collect::<Result<Vec<_>, syn::Error>>()?;
+			quote! { #path { #(#names),* } }
+		}
+		Fields::Unnamed(fields) => {
+			let names = (0..fields.unnamed.len()).map(|i| {
+				Ident::new(&format!("_{}", i), proc_macro2::Span::call_site())
+			});
+			quote! { #path(#(#names),*) }
+		}
+		Fields::Unit => path,
+	})
+}
+
+/// Generated code of a single template.
+struct CompiledTemplate {
+	tokens: proc_macro2::TokenStream,
+	/// `include_bytes!` calls for the template and all included files
+	include: Vec<proc_macro2::TokenStream>,
+	/// Functions defined with the `function` directive
+	functions: Vec<TemplateFunction>,
+	/// Items from `<#+ #>` blocks
+	features: Vec<FeatureBlock>,
+	/// Name and type (can be empty) from `parameter` directives
+	parameters: Vec<(String, String)>,
+	/// Length of all static text, used to preallocate when rendering
+	size_hint: usize,
+	debug_print: bool,
+	/// Placeholders in `tokens` and `functions` and the code which replaces
+	/// them in the debug output
+	debug_code: Vec<(String, String)>,
+	/// Identifies the template for the debug output file name
+	hash_key: String,
+	/// Names the template in errors, e.g. "the `html` template"
+	description: String,
+	/// Span of the template attribute
+	span: proc_macro2::Span,
+}
+
+/// A function defined in the template with the `function` directive.
+struct TemplateFunction {
+	name: String,
+	/// Name and parameters, e.g. `item<'t4>(&'t4 self, name: &'t4 str)`
+	signature: proc_macro2::TokenStream,
+	body: proc_macro2::TokenStream,
+	/// Imports, signature and body as written, to find identical definitions
+	code: String,
+}
+
+/// The items of a `<#+ #>` block.
+struct FeatureBlock {
+	/// Functions and constants
+	impl_items: Vec<proc_macro2::TokenStream>,
+	/// All other items
+	items: Vec<proc_macro2::TokenStream>,
+	/// Names of the items and if they are part of the impl
+	names: Vec<(String, bool)>,
+	/// The block as written, to find identical blocks
+	code: String,
+}
+
+/// The name of an item, which can not be defined twice.
+fn item_name(item: &Item) -> Option<String> {
+	let ident = match item {
+		Item::Const(item) => &item.ident,
+		Item::Enum(item) => &item.ident,
+		Item::Fn(item) => &item.sig.ident,
+		Item::Mod(item) => &item.ident,
+		Item::Static(item) => &item.ident,
+		Item::Struct(item) => &item.ident,
+		Item::Trait(item) => &item.ident,
+		Item::Type(item) => &item.ident,
+		Item::Union(item) => &item.ident,
+		_ => return None,
+	};
+	Some(ident.to_string())
+}
+
+impl CompiledTemplate {
+	fn body(&self) -> proc_macro2::TokenStream {
+		let CompiledTemplate { include, tokens, .. } = self;
+		quote! {
+			#(#include)*
+			#tokens
+			Ok(())
+		}
+	}
+}
+
+/// Reads, parses and generates the code for a template. `description` names
+/// the template in errors.
+fn compile_template(
+	source: &TemplateSource,
+	mut info: TemplateInfo,
+	description: String,
+) -> Result<CompiledTemplate, syn::Error>
+{
+	// All errors concerning the template itself are shown on the attribute
+	let source_lit = match source {
+		TemplateSource::Path(lit) | TemplateSource::Inline(lit) => lit,
+	};
+	let error = |msg: String| syn::Error::new_spanned(source_lit, msg);
+
+	let (path, read) = match source {
+		TemplateSource::Path(lit) => {
+			// Get template path
+			let mut path_absolute = manifest_dir().map_err(error)?;
+			path_absolute.push(lit.value());
+			let path = path_absolute.canonicalize().map_err(|e| {
+				error(format!(
+					"Could not find template file \"{}\": {}",
+					path_absolute.display(),
+					e
+				))
+			})?;
+			dbg_println!(
+				info,
+				"Looking for template in \"{}\"",
+				path.display()
+			);
+
+			// Read template file
+			let read = read_from_file(&path).map_err(|e| {
+				error(format!(
+					"Could not read template file \"{}\": {}",
+					path.display(),
+					e
+				))
+			})?;
+			info.include_stack.push(path.clone());
+			info.dependencies.push(path.clone());
+			(Some(path), read)
+		}
+		TemplateSource::Inline(lit) => (None, lit.value()),
 	};
 
-	if info.debug_print {
-		debug_to_file(path, &data);
+	// Parse template file
+	let mut data = parse_all(&mut info, &read).map_err(|e| {
+		let name = match &path {
+			Some(path) => display_path(path),
+			None => format!("#[{}]", TEMPLATE_SOURCE_MACRO),
+		};
+		error(format!("Parse error: {}", e.render(&name, &read)))
+	})?;
+
+	if let (true, Some(path)) = (info.debug_print, &path) {
+		debug_to_file(path, &data).map_err(|e| {
+			error(format!("Could not write template debug output: {}", e))
+		})?;
 	}
 
 	parse_postprocess(&mut data);
+	data.retain(|part| !matches!(part, Comment(_)));
 
 	let data = parse_optimize(data);
 
 	// Build code from template
-	info = TemplateInfo::default();
+	let dependencies = std::mem::take(&mut info.dependencies);
+	info = TemplateInfo {
+		debug_print: info.debug_print,
+		runtime: info.runtime,
+		..TemplateInfo::default()
+	};
 	let mut builder = String::new();
+	let mut size_hint = 0;
+	// Template functions as name, signature and body
+	let mut functions = Vec::new();
+	// The function which is currently generated and the escape settings to
+	// restore after it
+	let mut function: Option<(String, String, String, (String, String))> =
+		None;
+	let mut features = Vec::new();
+	let mut imports = String::new();
+	let mut parameters = Vec::new();
 	for part in data {
+		match part {
+			Directive(dir) if dir.name == "parameter" => {
+				let param = |key: &str| {
+					dir.params
+						.iter()
+						.find(|p| p.0 == key)
+						.map_or(String::new(), |p| p.1.clone())
+				};
+				parameters.push((param("name"), param("type")));
+				continue;
+			}
+			Feature(x) => {
+				features.push(x);
+				continue;
+			}
+			// Imports are valid for the whole template, like in T4. They are
+			// added to every function of this template, so templates of the
+			// same type can import the same names.
+			Directive(dir) if dir.name == "import" => {
+				for (_, namespace) in
+					dir.params.iter().filter(|p| p.0 == "namespace")
+				{
+					// Invalid imports were reported already in lenient mode
+					if syn::parse_str::<syn::UseTree>(namespace).is_ok() {
+						imports.push_str("#[allow(unused_imports)] use ");
+						imports.push_str(mark_user_code(namespace).as_ref());
+						imports.push(';');
+					}
+				}
+				continue;
+			}
+			Directive(dir) if dir.name == "function" => {
+				let (signature, body) =
+					function_signature(&dir).map_err(error)?;
+				let name = block_name(&dir).to_string();
+				let escape = (
+					info.print_postprocessor.clone(),
+					info.escape_writer.clone(),
+				);
+				function = Some((name, signature, body, escape));
+				continue;
+			}
+			Directive(dir) if dir.name == "endfunction" => {
+				if let Some((name, signature, body, escape)) = function.take() {
+					functions.push((name, signature, body));
+					info.print_postprocessor = escape.0;
+					info.escape_writer = escape.1;
+				}
+				continue;
+			}
+			_ => {}
+		}
+		let in_function = function.is_some();
+		let builder = match &mut function {
+			Some((_, _, body, _)) => body,
+			None => &mut builder,
+		};
 		match part {
 			Text(x) => {
+				if !in_function {
+					size_hint += x.len();
+				}
 				builder.push_str(generate_save_str_print(&x).as_ref());
 			}
 			Code(x) => {
-				builder.push_str(x.as_ref());
+				builder.push_str(mark_user_code(&x).as_ref());
 			}
 			Expr(x) => {
 				builder.push_str(generate_expression_print(&x, &info).as_ref());
@@ -222,85 +1449,243 @@ pub fn transform_template(
 			Directive(dir) => {
 				apply_directive(&mut info, &dir);
 			}
+			Comment(_) | Feature(_) => {}
 		}
 	}
 
 	dbg_println!(info, "Generated Code:\n{}", builder);
 
-	let tokens: proc_macro2::TokenStream =
-		builder.parse().expect("Parsing template code failed!");
-
-	// Build frame and insert
-	let (impl_generics, ty_generics, where_clause) =
-		macro_input.generics.split_for_impl();
-	let name = &macro_input.ident;
-	let path_str = path.to_str().expect("Invalid path");
-
-	let frame = quote! {
-		impl #impl_generics ::std::fmt::Display for #name #ty_generics #where_clause {
-			fn fmt(&self, _fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-				let _ = include_bytes!(#path_str);
-				#tokens
-				Ok(())
-			}
+	let parse_code = |code: &str| -> Result<proc_macro2::TokenStream, syn::Error> {
+		let tokens = code.parse().map_err(|e| {
+			error(format!(
+				"The code in the template is not valid rust, check for \
+				 unclosed brackets or strings: {}",
+				e
+			))
+		})?;
+		// Point errors in template code to the template attribute instead of
+		// the derive.
+		Ok(respan_user_code(tokens, source_lit.span(), &mut false))
+	};
+	// Code from template files keeps the comments with the template locations
+	// (see `mark_source_lines`), so rustc errors show the template line. With
+	// debug output the code is inserted into the written file as text and
+	// replaced by a placeholder until then. Otherwise it is included from a
+	// generated file. Inline templates are short, errors in them point to the
+	// attribute.
+	let mut debug_code = Vec::new();
+	let mut parse_body = |code: &str| -> Result<_, syn::Error> {
+		let tokens = parse_code(code)?;
+		if !info.debug_print {
+			return Ok(match path {
+				Some(_) => include_generated(code).unwrap_or(tokens),
+				None => tokens,
+			});
 		}
+		let mut hasher = DefaultHasher::new();
+		hasher.write(code.as_bytes());
+		let placeholder = format!("__t4rust_code_{:016x}", hasher.finish());
+		let code = code.replace(USER_CODE_START, "").replace(USER_CODE_END, "");
+		debug_code.push((placeholder.clone(), format!("\n{}\n", code)));
+		let placeholder = Ident::new(&placeholder, source_lit.span());
+		Ok(quote! { #placeholder })
 	};
+	let imports_code = imports;
+	let imports = parse_code(&imports_code)?;
+	let tokens = parse_body(&builder)?;
+	let tokens = quote! { #imports #tokens };
+	let functions = functions
+		.into_iter()
+		.map(|(name, signature, body)| {
+			let body_tokens = parse_body(&body)?;
+			Ok(TemplateFunction {
+				name,
+				signature: parse_code(&signature)?,
+				body: quote! { #imports #body_tokens },
+				code: format!("{}{}{}", imports_code, signature, body),
+			})
+		})
+		.collect::<Result<Vec<_>, syn::Error>>()?;
+
+	// Functions and constants become part of the type, all other items are
+	// only visible to the generated code.
+	let features = features
+		.into_iter()
+		.map(|code| {
+			let mut block = FeatureBlock {
+				impl_items: Vec::new(),
+				items: Vec::new(),
+				names: Vec::new(),
+				code,
+			};
+			let tokens = parse_code(&mark_user_code(&block.code))?;
+			for item in syn::parse2::<syn::File>(tokens)?.items {
+				let in_impl = matches!(item, Item::Fn(_) | Item::Const(_));
+				if let Some(name) = item_name(&item) {
+					block.names.push((name, in_impl));
+				}
+				if in_impl {
+					block.impl_items.push(quote! { #item });
+				} else {
+					block.items.push(quote! { #item });
+				}
+			}
+			Ok(block)
+		})
+		.collect::<Result<Vec<_>, syn::Error>>()?;
 
-	// We could return the code now. The problem is that span information are
-	// missing and the error messages are awful.
-	// So instead, we write to a file and include! this file, which still does
-	// not give us nice errors but at least includes source code.
-	if !info.debug_print {
-		proc_macro::TokenStream::from(frame)
-	} else {
-		// Unfortunately we have no access to OUT_DIR like build scripts so we
-		// try to emulate that partially.
-
-		// Use hash of template path as filename
-		let mut hasher = DefaultHasher::new();
-		hasher.write(path_str.as_bytes());
+	// Make cargo rebuild when the template or any included file changes
+	let include = dependencies
+		.iter()
+		.map(|p| {
+			let p = path_to_str(p).map_err(error)?;
+			Ok(quote! { let _ = include_bytes!(#p); })
+		})
+		.collect::<Result<Vec<_>, syn::Error>>()?;
+
+	let hash_key = match &path {
+		Some(path) => path_to_str(path).map_err(error)?.to_string(),
+		None => read,
+	};
 
-		let out_dir = if let Ok(target_dir) = std::env::var("CARGO_TARGET_DIR")
-		{
-			PathBuf::from(target_dir)
-		} else {
-			let dir = std::env::var("CARGO_MANIFEST_DIR")
-				.expect("CARGO_MANIFEST_DIR not set");
-			PathBuf::from(dir).join("target")
-		};
+	Ok(CompiledTemplate {
+		tokens,
+		include,
+		functions,
+		features,
+		parameters,
+		size_hint,
+		debug_print: info.debug_print,
+		debug_code,
+		hash_key,
+		description,
+		span: source_lit.span(),
+	})
+}
 
-		let code_path = out_dir
-			.join("t4rust")
-			.join(&hasher.finish().to_string())
-			.with_extension("rs");
+/// Directory for generated code. Unfortunately we have no access to OUT_DIR
+/// like build scripts so we try to emulate that partially.
+fn generated_dir() -> Result<PathBuf, String> {
+	let target_dir = match std::env::var("CARGO_TARGET_DIR") {
+		Ok(target_dir) => PathBuf::from(target_dir),
+		Err(_) => manifest_dir()?.join("target"),
+	};
+	Ok(target_dir.join("t4rust"))
+}
 
-		std::fs::create_dir_all(code_path.parent().unwrap())
-			.expect("Failed to create output path");
+/// Writes generated code of a template file into the target directory and
+/// returns an `include!` of it. Errors in the code then point into this file,
+/// where the template code is marked with its location in the template.
+/// Returns `None` if the file can not be written.
+fn include_generated(code: &str) -> Option<proc_macro2::TokenStream> {
+	// The file counts as code of the user crate, lints in the generated parts
+	// are no mistakes of the user.
+	let code = format!(
+		"{{\n#![allow(clippy::all, clippy::pedantic, clippy::nursery)]\n{}\n}}\n",
+		code.replace(USER_CODE_START, "").replace(USER_CODE_END, "")
+	);
+	let mut hasher = DefaultHasher::new();
+	hasher.write(code.as_bytes());
+	let dir = generated_dir().ok()?;
+	let path = dir.join(hasher.finish().to_string()).with_extension("rs");
+	// The name depends on the content, so existing files are up to date and
+	// not touching them avoids rebuilds.
+	if !path.exists() {
+		std::fs::create_dir_all(&dir).ok()?;
+		// Builds running in parallel should never see a half written file
+		let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+		std::fs::write(&tmp, code).ok()?;
+		std::fs::rename(&tmp, &path).ok()?;
+	}
+	let path = path.to_str()?;
+	Some(quote! { include!(#path); })
+}
 
-		// Write file
-		std::fs::write(&code_path, frame.to_string().as_bytes())
-			.expect("Failed to write compiled template");
+fn manifest_dir() -> Result<PathBuf, String> {
+	std::env::var("CARGO_MANIFEST_DIR")
+		.map(PathBuf::from)
+		.map_err(|_| "CARGO_MANIFEST_DIR is not set".to_string())
+}
 
-		let code_path_str = code_path.to_str();
-		proc_macro::TokenStream::from(quote! { include!(#code_path_str); })
-	}
+/// Paths are passed as string literals to macros, so they need to be UTF-8.
+fn path_to_str(path: &Path) -> Result<&str, String> {
+	path.to_str()
+		.ok_or_else(|| format!("The path {:?} is not valid UTF-8", path))
 }
 
-fn generate_expression_print(print_expr: &str, info: &TemplateInfo) -> String {
-	if info.print_postprocessor.is_empty() {
-		format!("write!(_fmt, \"{{}}\", {})?;\n", print_expr)
+fn generate_expression_print(expr: &Expression, info: &TemplateInfo) -> String {
+	let print_expr = mark_user_code(&expr.code);
+	let format = format!("{:?}", expr.format_string());
+	if expr.raw {
+		format!("write!(_fmt, {}, {})?;\n", format, print_expr)
+	} else if !info.escape_writer.is_empty() {
+		format!(
+			"write!(::t4rust::EscapeWriter::new(_fmt, {}), {}, {})?;\n",
+			info.escape_writer, format, print_expr
+		)
+	} else if info.print_postprocessor.is_empty() {
+		format!("write!(_fmt, {}, {})?;\n", format, print_expr)
 	} else {
 		format!(
 			"{{
-			let _s = format!(\"{{}}\", {});
+			let _s = format!({}, {});
 			let _s_transfomed = {}(&_s);
 			_fmt.write_str(&_s_transfomed)?;
 			}}\n",
-			print_expr, info.print_postprocessor
+			format, print_expr, info.print_postprocessor
 		)
 	}
 }
 
+/// Surrounds code written in the template with markers, so
+/// `respan_user_code` can find it again after tokenizing.
+fn mark_user_code(code: &str) -> String {
+	// The newline ends line comments at the end of the code
+	format!(" {} {}\n{} ", USER_CODE_START, code, USER_CODE_END)
+}
+
+/// Removes the markers from `mark_user_code` and gives all tokens in between
+/// the passed span.
+fn respan_user_code(
+	tokens: proc_macro2::TokenStream,
+	span: proc_macro2::Span,
+	in_user_code: &mut bool,
+) -> proc_macro2::TokenStream
+{
+	let mut result = proc_macro2::TokenStream::new();
+	for token in tokens {
+		let token = match token {
+			TokenTree::Ident(ref ident) if ident == USER_CODE_START => {
+				*in_user_code = true;
+				continue;
+			}
+			TokenTree::Ident(ref ident) if ident == USER_CODE_END => {
+				*in_user_code = false;
+				continue;
+			}
+			TokenTree::Group(group) => {
+				let opened_in_user_code = *in_user_code;
+				let stream = respan_user_code(group.stream(), span, in_user_code);
+				let mut new_group = Group::new(group.delimiter(), stream);
+				new_group.set_span(if opened_in_user_code {
+					span
+				} else {
+					group.span()
+				});
+				TokenTree::Group(new_group)
+			}
+			mut token => {
+				if *in_user_code {
+					token.set_span(span);
+				}
+				token
+			}
+		};
+		result.extend(std::iter::once(token));
+	}
+	result
+}
+
 fn generate_save_str_print(print_str: &str) -> String {
 	let mut max_sharp_count = 0;
 	let mut cur_sharp_count = 0;
@@ -325,33 +1710,44 @@ fn read_from_file(path: &Path) -> Result<String, std::io::Error> {
 	Ok(contents)
 }
 
-fn debug_to_file(path: &Path, data: &[TemplatePart]) {
+fn debug_to_file(path: &Path, data: &[TemplatePart]) -> std::io::Result<()> {
 	let mut pathbuf = PathBuf::new();
 	pathbuf.push(path);
 	pathbuf.set_extension("tt.out");
 	let writepath = pathbuf.as_path();
-	if let Ok(mut file) = File::create(writepath) {
-		for var in data {
-			match *var {
-				Code(ref x) => {
-					write!(file, "Code:").unwrap();
-					file.write_all(x.as_bytes()).unwrap();
-				}
-				Text(ref x) => {
-					write!(file, "Text:").unwrap();
-					file.write_all(x.as_bytes()).unwrap();
-				}
-				Expr(ref x) => {
-					write!(file, "Expr:").unwrap();
-					file.write_all(x.as_bytes()).unwrap();
-				}
-				Directive(ref dir) => {
-					write!(file, "Dir:{:?}", dir).unwrap();
+	let mut file = File::create(writepath)?;
+	for var in data {
+		match *var {
+			Code(ref x) => {
+				write!(file, "Code:")?;
+				file.write_all(x.as_bytes())?;
+			}
+			Text(ref x) => {
+				write!(file, "Text:")?;
+				file.write_all(x.as_bytes())?;
+			}
+			Expr(ref x) => {
+				write!(file, "{}:", if x.raw { "RawExpr" } else { "Expr" })?;
+				file.write_all(x.code.as_bytes())?;
+				if !x.format.is_empty() {
+					write!(file, ":{}", x.format)?;
 				}
 			}
-			writeln!(file).unwrap();
+			Directive(ref dir) => {
+				write!(file, "Dir:{:?}", dir)?;
+			}
+			Comment(ref x) => {
+				write!(file, "Comment:")?;
+				file.write_all(x.as_bytes())?;
+			}
+			Feature(ref x) => {
+				write!(file, "Feature:")?;
+				file.write_all(x.as_bytes())?;
+			}
 		}
+		writeln!(file)?;
 	}
+	Ok(())
 }
 
 /// Transforms template code into an intermediate representation
@@ -362,35 +1758,229 @@ fn parse_all(
 {
 	let mut builder: Vec<TemplatePart> = Vec::new();
 	let mut cur = input;
+	let d = info.delimiters.clone();
+	// The last block ended with `-#>`
+	let mut trim_text_start = false;
+	let mut extends = None;
+	// Positions of the `block` directives which are not closed yet
+	let mut open_blocks = Vec::new();
+	// Names and positions of all `block` directives
+	let mut block_names: Vec<(String, usize)> = Vec::new();
+	// Position of the `function` directive which is not closed yet
+	let mut open_function = None;
+	// Position of the first code, expression or text outside of blocks and
+	// functions, which is not allowed in an extending template
+	let mut outside_content = None;
 
 	dbg_println!(info, "Reading template");
 
 	while !cur.is_empty() {
-		let (crest, content) = parse_text(info, cur)?;
+		let start = input.len() - cur.len();
+		let (crest, mut content) = parse_text(info, cur)
+			.map_err(|e| TemplateError { index: start, ..e })?;
+		if trim_text_start {
+			content = content.trim_start().to_string();
+		}
+		if open_blocks.is_empty()
+			&& open_function.is_none()
+			&& !content.trim().is_empty()
+		{
+			outside_content
+				.get_or_insert(start + cur.len() - cur.trim_start().len());
+		}
 		builder.push(Text(content));
 		cur = crest;
 		dbg_println!(info, "");
 
 		// Read code block
-		if let Ok((rest, _)) = expression_start(cur) {
+		let start = input.len() - cur.len();
+		if let Ok((rest, _)) = comment_start(&d, cur) {
+			dbg_print!(info, " comment start");
+			let (crest, content) = read_comment(&d, rest).map_err(|_| {
+				TemplateError {
+					index: start,
+					reason: "Unclosed comment block".into(),
+				}
+			})?;
+			builder.push(Comment(content.to_string()));
+			cur = crest;
+			trim_text_start = false;
+		} else if let Ok((rest, (trim_before, raw, debug))) = alt((
+			map(|s| expression_start(&d, s), |trim| (trim, false, false)),
+			map(|s| raw_expression_start(&d, s), |trim| (trim, true, false)),
+			map(|s| debug_expression_start(&d, s), |trim| (trim, false, true)),
+		))(cur)
+		{
 			dbg_print!(info, " expression start");
-			let (crest, content) = parse_code(info, rest)?;
-			builder.push(Expr(content));
+			let (crest, content, trim_after) = parse_code(info, rest, start)?;
+			let mut expr = parse_expression(&content, raw, debug)
+				.map_err(|e| TemplateError { index: start, ..e })?;
+			expr.code = mark_source_lines(info, input, rest, &expr.code);
+			trim_text_end(&mut builder, trim_before);
+			if open_blocks.is_empty() && open_function.is_none() {
+				outside_content.get_or_insert(start);
+			}
+			builder.push(Expr(expr));
+			trim_text_start = trim_after;
 			cur = crest;
-		} else if let Ok((rest, _)) = template_directive_start(cur) {
+		} else if let Ok((rest, trim_before)) = feature_start(&d, cur) {
+			dbg_print!(info, " feature start");
+			let (crest, content, trim_after) = parse_code(info, rest, start)?;
+			syn::parse_str::<syn::File>(&content).map_err(|e| TemplateError {
+				index: start,
+				reason: format!("Invalid items in the feature block: {}", e),
+			})?;
+			trim_text_end(&mut builder, trim_before);
+			builder.push(Feature(mark_source_lines(info, input, rest, &content)));
+			trim_text_start = trim_after;
+			cur = crest;
+		} else if let Ok((rest, trim_before)) = template_directive_start(&d, cur)
+		{
 			dbg_print!(info, " directive start");
-			let (crest, content) = parse_code(info, rest)?;
+			let (crest, content, trim_after) = parse_code(info, rest, start)?;
+			trim_text_end(&mut builder, trim_before);
+			trim_text_start = trim_after;
 			let dir = parse_directive(&content);
 			dbg_println!(info, " Directive: {:?}", dir);
+			if let Ok((_, dir)) = &dir {
+				check_directive(info, dir)
+					.map_err(|e| TemplateError { index: start, ..e })?;
+			}
 			match dir {
+				Ok((_, dir)) if dir.name == "raw" => {
+					let (text, rest, (end_trim_before, end_trim_after)) =
+						read_raw(&d, crest).ok_or_else(|| TemplateError {
+							index: start,
+							reason: format!(
+								"Unclosed raw block, expected `{}@ endraw {}`",
+								d.open, d.close
+							),
+						})?;
+					let mut text = text;
+					if trim_text_start {
+						text = text.trim_start();
+					}
+					if end_trim_before {
+						text = text.trim_end();
+					}
+					if open_blocks.is_empty()
+						&& open_function.is_none()
+						&& !text.trim().is_empty()
+					{
+						outside_content.get_or_insert(start);
+					}
+					builder.push(Directive(dir));
+					builder.push(Text(text.to_string()));
+					builder.push(Directive(TemplateDirective {
+						name: "endraw".into(),
+						params: Vec::new(),
+					}));
+					trim_text_start = end_trim_after;
+					cur = rest;
+					continue;
+				}
+				Ok((_, dir)) if dir.name == "endraw" => {
+					return Err(TemplateError {
+						index: start,
+						reason: "Found `endraw` without a matching `raw`".into(),
+					});
+				}
+				Ok((_, dir)) if dir.name == "extends" => {
+					if extends.is_some() {
+						return Err(TemplateError {
+							index: start,
+							reason: "Only one `extends` directive is allowed"
+								.into(),
+						});
+					}
+					extends = Some((start, dir));
+				}
+				Ok((_, dir)) if dir.name == "block" => {
+					let name = block_name(&dir).to_string();
+					if name.is_empty() {
+						return Err(TemplateError {
+							index: start,
+							reason: "The block directive needs a name parameter"
+								.into(),
+						});
+					}
+					if block_names.iter().any(|(n, _)| *n == name) {
+						return Err(TemplateError {
+							index: start,
+							reason: format!(
+								"There is already a block called `{}`",
+								name
+							),
+						});
+					}
+					block_names.push((name, start));
+					open_blocks.push(start);
+					builder.push(Directive(dir));
+				}
+				Ok((_, dir)) if dir.name == "endblock" => {
+					if open_blocks.pop().is_none() {
+						return Err(TemplateError {
+							index: start,
+							reason: "Found `endblock` without a matching `block`"
+								.into(),
+						});
+					}
+					builder.push(Directive(dir));
+				}
+				Ok((_, dir)) if dir.name == "function" => {
+					if open_function.is_some() {
+						return Err(TemplateError {
+							index: start,
+							reason: "Functions can not be nested".into(),
+						});
+					}
+					function_signature(&dir)
+						.map_err(|reason| TemplateError { index: start, reason })?;
+					open_function = Some(start);
+					builder.push(Directive(dir));
+				}
+				Ok((_, dir)) if dir.name == "endfunction" => {
+					if open_function.take().is_none() {
+						return Err(TemplateError {
+							index: start,
+							reason: "Found `endfunction` without a matching \
+							         `function`"
+								.into(),
+						});
+					}
+					builder.push(Directive(dir));
+				}
+				Ok((_, dir))
+					if dir.name == "parameter"
+						&& !dir.params.iter().any(|p| p.0 == "name") =>
+				{
+					return Err(TemplateError {
+						index: start,
+						reason: "The parameter directive needs a name parameter"
+							.into(),
+					});
+				}
+				Ok((_, dir)) if dir.name == "include" => {
+					let parts = parse_file(info, &dir)
+						.map_err(|e| TemplateError { index: start, ..e })?;
+					if open_blocks.is_empty()
+						&& open_function.is_none()
+						&& parts.iter().any(|part| match part {
+							Text(text) => !text.trim().is_empty(),
+							Code(_) | Expr(_) => true,
+							_ => false,
+						}) {
+						outside_content.get_or_insert(start);
+					}
+					builder.extend(parts);
+				}
 				Ok((_, dir)) => {
 					apply_directive(info, &dir);
 					builder.push(Directive(dir));
 				}
 				Err(_) => {
-					println!("Malformed directive: {}", &content);
 					return Err(TemplateError {
-						index: 0,
+						index: start,
 						reason: format!(
 							"Could not understand the directive: {}",
 							&content
@@ -399,21 +1989,433 @@ fn parse_all(
 				}
 			}
 			cur = crest;
-		} else if let Ok((rest, _)) = code_start(cur) {
+		} else if let Ok((rest, trim_before)) = code_start(&d, cur) {
 			dbg_print!(info, " code start");
-			let (crest, content) = parse_code(info, rest)?;
-			builder.push(Code(content));
+			let (crest, content, trim_after) = parse_code(info, rest, start)?;
+			trim_text_end(&mut builder, trim_before);
+			if open_blocks.is_empty() && open_function.is_none() {
+				outside_content.get_or_insert(start);
+			}
+			builder.push(Code(mark_source_lines(info, input, rest, &content)));
+			trim_text_start = trim_after;
 			cur = crest;
 		}
 
 		dbg_println!(info, " Rest: {:?}", &cur);
 	}
 
+	if let Some(index) = open_function {
+		return Err(TemplateError {
+			index,
+			reason: "Unclosed function, expected `endfunction`".into(),
+		});
+	}
+	if let Some(&index) = open_blocks.last() {
+		return Err(TemplateError {
+			index,
+			reason: "Unclosed block, expected `endblock`".into(),
+		});
+	}
+
 	dbg_println!(info, "\nTemplate ok!");
 
+	if let Some((index, dir)) = extends {
+		if let Some(index) = outside_content {
+			return Err(TemplateError {
+				index,
+				reason: "Only directives, functions and features are allowed \
+				         outside of blocks in an extending template"
+					.into(),
+			});
+		}
+		return extend_template(info, index, &dir, &block_names, builder);
+	}
 	Result::Ok(builder)
 }
 
+/// Removes all whitespace at the end of the last text for blocks starting
+/// with `<#-`.
+fn trim_text_end(builder: &mut [TemplatePart], trim: bool) {
+	if let (true, Some(Text(text))) = (trim, builder.last_mut()) {
+		text.truncate(text.trim_end().len());
+	}
+}
+
+/// Splits the format spec from an expression block and checks both, so syntax
+/// errors can be reported with the position in the template. Debug
+/// expressions (`<#? #>`) get a `?` added to their spec.
+fn parse_expression(
+	content: &str,
+	raw: bool,
+	debug: bool,
+) -> Result<Expression, TemplateError>
+{
+	let (code, format) = split_format_spec(content);
+	let format = if debug && !format.ends_with('?') {
+		format!("{}?", format)
+	} else {
+		format.to_string()
+	};
+	syn::parse_str::<syn::Expr>(code).map_err(|e| TemplateError {
+		index: 0,
+		reason: format!("Invalid expression `{}`: {}", code.trim(), e),
+	})?;
+	check_format_spec(&format).map_err(|reason| TemplateError {
+		index: 0,
+		reason: format!("Invalid format spec `{}`: {}", format, reason),
+	})?;
+	Ok(Expression { code: code.to_string(), format, raw })
+}
+
+/// Splits `expr :spec` at the first colon which follows a whitespace and is
+/// not nested in brackets or part of a `::` or literal.
+fn split_format_spec(content: &str) -> (&str, &str) {
+	let bytes = content.as_bytes();
+	let mut depth = 0usize;
+	let mut i = 0;
+	while i < bytes.len() {
+		match bytes[i] {
+			b'(' | b'[' | b'{' => depth += 1,
+			b')' | b']' | b'}' => depth = depth.saturating_sub(1),
+			b'"' => i = skip_string(bytes, i, 0),
+			b'r' if !is_ident_byte(bytes, i.wrapping_sub(1)) => {
+				let hashes =
+					bytes[i + 1..].iter().take_while(|&&b| b == b'#').count();
+				if bytes.get(i + 1 + hashes) == Some(&b'"') {
+					i = skip_string(bytes, i + 1 + hashes, hashes);
+				}
+			}
+			b'\'' => i = skip_char(content, i),
+			b':' if depth == 0
+				&& i > 0 && bytes[i - 1].is_ascii_whitespace()
+				&& bytes.get(i + 1) != Some(&b':') =>
+			{
+				return (&content[..i], content[i + 1..].trim());
+			}
+			_ => {}
+		}
+		i += 1;
+	}
+	(content, "")
+}
+
+fn is_ident_byte(bytes: &[u8], i: usize) -> bool {
+	matches!(bytes.get(i), Some(b) if b.is_ascii_alphanumeric() || *b == b'_')
+}
+
+/// Returns the index of the closing quote of the char literal starting at
+/// `start`. Lifetimes have no closing quote, for them `start` is returned.
+fn skip_char(content: &str, start: usize) -> usize {
+	let bytes = content.as_bytes();
+	if bytes.get(start + 1) == Some(&b'\\') {
+		let mut i = start + 2;
+		while i + 1 < bytes.len() && bytes[i + 1] != b'\'' {
+			i += 1;
+		}
+		i + 1
+	} else {
+		let end = content[start + 1..]
+			.chars()
+			.next()
+			.map_or(start, |c| start + c.len_utf8() + 1);
+		if content[end..].starts_with('\'') {
+			end
+		} else {
+			start
+		}
+	}
+}
+
+/// Returns the index of the closing quote of the string starting at `start`,
+/// or of the last `#` for raw strings.
+fn skip_string(bytes: &[u8], start: usize, hashes: usize) -> usize {
+	let mut i = start + 1;
+	while i < bytes.len() {
+		if hashes == 0 && bytes[i] == b'\\' {
+			i += 1;
+		} else if bytes[i] == b'"'
+			&& bytes[i + 1..].iter().take_while(|&&b| b == b'#').count()
+				>= hashes
+		{
+			return i + hashes;
+		}
+		i += 1;
+	}
+	i
+}
+
+/// Checks a format spec like `>10.2` or `#x` against the grammar of
+/// `std::fmt`. Arguments by position (`1$`, `.*`) are not supported, named
+/// arguments (`width$`) refer to variables in the template.
+fn check_format_spec(spec: &str) -> Result<(), String> {
+	let mut rest = spec;
+	let is_align = |c: char| c == '<' || c == '^' || c == '>';
+	let mut chars = rest.chars();
+	match (chars.next(), chars.next()) {
+		(Some(fill), Some(align)) if is_align(align) => {
+			if fill == '{' || fill == '}' {
+				return Err("`{` and `}` can not be used as fill".into());
+			}
+			rest = &rest[fill.len_utf8() + 1..];
+		}
+		(Some(align), _) if is_align(align) => rest = &rest[1..],
+		_ => {}
+	}
+	for flag in &["+", "-", "#", "0"] {
+		if rest.starts_with(flag) && !rest[1..].starts_with('$') {
+			rest = &rest[1..];
+		}
+	}
+	rest = check_format_count(rest)?;
+	if let Some(precision) = rest.strip_prefix('.') {
+		if precision.starts_with('*') {
+			return Err("`.*` is not supported, use a named argument".into());
+		}
+		let after = check_format_count(precision)?;
+		if after.len() == precision.len() {
+			return Err("expected a precision after `.`".into());
+		}
+		rest = after;
+	}
+	match rest {
+		"" | "?" | "x?" | "X?" | "x" | "X" | "o" | "b" | "e" | "E" | "p" => {
+			Ok(())
+		}
+		_ => Err(format!("unexpected `{}`", rest)),
+	}
+}
+
+/// Skips a width or precision, which is a number or a `name$`.
+fn check_format_count(s: &str) -> Result<&str, String> {
+	let digits = s.bytes().take_while(u8::is_ascii_digit).count();
+	if digits > 0 {
+		let (count, rest) = s.split_at(digits);
+		if rest.starts_with('$') {
+			return Err(format!(
+				"positional argument `{}$` is not supported",
+				count
+			));
+		}
+		return Ok(rest);
+	}
+	let end = s
+		.find(|c: char| !(c.is_alphanumeric() || c == '_'))
+		.unwrap_or(s.len());
+	match s[end..].strip_prefix('$') {
+		Some(rest) if syn::parse_str::<syn::Ident>(&s[..end]).is_ok() => {
+			Ok(rest)
+		}
+		// Probably the type, e.g. `x` or `e`
+		_ => Ok(s),
+	}
+}
+
+/// Reads and parses the file referenced by an `include` or `extends`
+/// directive, so the resulting parts can be spliced into the current template.
+fn parse_file(
+	info: &mut TemplateInfo,
+	directive: &TemplateDirective,
+) -> Result<Vec<TemplatePart>, TemplateError>
+{
+	let file = directive
+		.params
+		.iter()
+		.find(|p| p.0 == "file")
+		.map(|p| p.1.as_str())
+		.ok_or_else(|| TemplateError {
+			index: 0,
+			reason: format!(
+				"The {} directive needs a file parameter",
+				directive.name
+			),
+		})?;
+
+	let (verb, cycle) = if directive.name == "include" {
+		("included", "Include")
+	} else {
+		("extended", "Inheritance")
+	};
+
+	// Resolve relative to the including file, inline templates are relative
+	// to the crate root like #[TemplatePath].
+	let mut path = match info.include_stack.last().and_then(|p| p.parent()) {
+		Some(current_dir) => current_dir.to_path_buf(),
+		None => manifest_dir()
+			.map_err(|reason| TemplateError { index: 0, reason })?,
+	};
+	path.push(file);
+	let path = path.canonicalize().map_err(|e| TemplateError {
+		index: 0,
+		reason: format!("Could not find {} file {:?}: {}", verb, path, e),
+	})?;
+
+	if info.include_stack.contains(&path) {
+		let chain = info
+			.include_stack
+			.iter()
+			.chain(std::iter::once(&path))
+			.map(|p| display_path(p))
+			.collect::<Vec<_>>()
+			.join(" -> ");
+		return Err(TemplateError {
+			index: 0,
+			reason: format!("{} cycle detected: {}", cycle, chain),
+		});
+	}
+
+	dbg_println!(info, "Reading {} file \"{}\"", verb, path.display());
+	let content = read_from_file(&path).map_err(|e| TemplateError {
+		index: 0,
+		reason: format!("Could not read {} file {:?}: {}", verb, path, e),
+	})?;
+
+	if !info.dependencies.contains(&path) {
+		info.dependencies.push(path.clone());
+	}
+	info.include_stack.push(path);
+	let parts = parse_all(info, &content);
+	let path = info.include_stack.pop().unwrap();
+	parts.map_err(|e| TemplateError {
+		index: 0,
+		reason: format!(
+			"{}\n{} from",
+			e.render(&display_path(&path), &content),
+			verb
+		),
+	})
+}
+
+/// Parses the template referenced by an `extends` directive and replaces the
+/// content of its blocks with the blocks of the same name from `child`.
+///
+/// `index` is the position of the `extends` directive and `block_names` the
+/// names and positions of the blocks in `child`.
+fn extend_template(
+	info: &mut TemplateInfo,
+	index: usize,
+	directive: &TemplateDirective,
+	block_names: &[(String, usize)],
+	child: Vec<TemplatePart>,
+) -> Result<Vec<TemplatePart>, TemplateError>
+{
+	let mut blocks = Vec::new();
+	let mut result = Vec::new();
+	let mut parts = child.into_iter();
+	while let Some(part) = parts.next() {
+		match part {
+			Directive(dir) if dir.name == "block" => {
+				let content = read_block(&mut parts);
+				blocks.push((block_name(&dir).to_string(), content));
+			}
+			Directive(dir) if dir.name == "function" => {
+				result.push(Directive(dir));
+				for part in parts.by_ref() {
+					let end = matches!(
+						&part,
+						Directive(dir) if dir.name == "endfunction"
+					);
+					result.push(part);
+					if end {
+						break;
+					}
+				}
+			}
+			// Directives, functions and features outside of blocks apply to
+			// the whole template, whitespace and comments are ignored. Other
+			// content was already rejected by `parse_all`.
+			Directive(dir) => result.push(Directive(dir)),
+			Feature(items) => result.push(Feature(items)),
+			Comment(_) | Text(_) | Code(_) | Expr(_) => {}
+		}
+	}
+
+	let mut parts = parse_file(info, directive)
+		.map_err(|e| TemplateError { index, ..e })?
+		.into_iter();
+	// Blocks of the extended template which are inside of a replaced block,
+	// overriding them has no effect.
+	let mut dropped = Vec::new();
+	while let Some(part) = parts.next() {
+		let replacement = match &part {
+			Directive(dir) if dir.name == "block" => {
+				blocks.iter().position(|(name, _)| name == block_name(dir))
+			}
+			_ => None,
+		};
+		result.push(part);
+		if let Some(i) = replacement {
+			for part in read_block(&mut parts) {
+				if let Directive(dir) = part {
+					if dir.name == "block" {
+						dropped.push(block_name(&dir).to_string());
+					}
+				}
+			}
+			result.extend(blocks.swap_remove(i).1);
+		}
+	}
+
+	if let Some((name, _)) =
+		blocks.iter().find(|(name, _)| !dropped.contains(name))
+	{
+		let index = block_names
+			.iter()
+			.find(|(n, _)| n == name)
+			.map_or(index, |(_, start)| *start);
+		return Err(TemplateError {
+			index,
+			reason: format!(
+				"The block `{}` does not exist in the extended template",
+				name
+			),
+		});
+	}
+	Ok(result)
+}
+
+fn block_name(directive: &TemplateDirective) -> &str {
+	directive
+		.params
+		.iter()
+		.find(|p| p.0 == "name")
+		.map_or("", |p| p.1.as_str())
+}
+
+/// Takes all parts up to and including the `endblock` of the current block.
+fn read_block(
+	parts: &mut impl Iterator<Item = TemplatePart>,
+) -> Vec<TemplatePart>
+{
+	let mut content = Vec::new();
+	let mut depth = 0;
+	for part in parts {
+		match &part {
+			Directive(dir) if dir.name == "block" => depth += 1,
+			Directive(dir) if dir.name == "endblock" => {
+				if depth == 0 {
+					content.push(part);
+					break;
+				}
+				depth -= 1;
+			}
+			_ => {}
+		}
+		content.push(part);
+	}
+	content
+}
+
+/// Shortens paths within the crate to be relative to the crate root.
+fn display_path(path: &Path) -> String {
+	manifest_dir()
+		.ok()
+		.and_then(|dir| dir.canonicalize().ok())
+		.and_then(|dir| path.strip_prefix(dir).ok().map(Path::to_path_buf))
+		.unwrap_or_else(|| path.to_path_buf())
+		.display()
+		.to_string()
+}
+
 fn parse_text<'a>(
 	info: &TemplateInfo,
 	input: &'a str,
@@ -423,19 +2425,19 @@ fn parse_text<'a>(
 	let mut cur = input;
 
 	loop {
-		let read = read_text(cur);
+		let read = read_text(&info.delimiters, cur);
 		match read {
 			Ok((rest, done)) => {
-				content.push_str(&done);
+				content.push_str(done);
 				if rest.is_empty() {
 					return Ok((rest, content));
 				}
 				cur = rest;
 				dbg_print!(info, " take text: {:?}", &done);
 
-				if let Ok((rest, _)) = double_code_start(cur) {
+				if let Ok((rest, _)) = double_code_start(&info.delimiters, cur) {
 					dbg_print!(info, " double-escape");
-					content.push_str("<#");
+					content.push_str(&info.delimiters.open);
 
 					if rest.is_empty() {
 						return Ok((rest, content));
@@ -445,53 +2447,154 @@ fn parse_text<'a>(
 					return Ok((rest, content));
 				}
 			}
-			Err(_) => {
-				if let Ok((rest, done)) = till_end(cur) {
-					if rest.is_empty() {
-						content.push_str(&done);
-						return Ok((rest, content));
+			Err(_) => {
+				if let Ok((rest, done)) = till_end(cur) {
+					if rest.is_empty() {
+						content.push_str(done);
+						return Ok((rest, content));
+					}
+				}
+				return Err(TemplateError {
+					index: 0,
+					reason: "Reached unknown parsing state (!read_text > \
+					         !till_end)"
+						.into(),
+				});
+			}
+		}
+
+		dbg_println!(info, " Rest: {:?}", &cur);
+	}
+}
+
+/// Marks every line of the code, which starts at `code` in `input`, with its
+/// location in the template file, e.g. `/* templates/page.tt:12:5 */`. The
+/// comments are kept in the generated file, so rustc errors in it show the
+/// template line.
+fn mark_source_lines(
+	info: &TemplateInfo,
+	input: &str,
+	code: &str,
+	content: &str,
+) -> String
+{
+	let path = match info.include_stack.last() {
+		Some(path) => display_path(path),
+		None => return content.to_string(),
+	};
+	let start = input.len() - code.len();
+	let first_line = input[..start].matches('\n').count() + 1;
+	let line_start = input[..start].rfind('\n').map_or(0, |i| i + 1);
+	let mut result = format!(
+		"/* {}:{}:{} */",
+		path,
+		first_line,
+		input[line_start..start].chars().count() + 1
+	);
+
+	let bytes = content.as_bytes();
+	let mut copied = 0;
+	let mut i = 0;
+	while i < bytes.len() {
+		match bytes[i] {
+			b'\n' => {
+				result.push_str(&content[copied..=i]);
+				copied = i + 1;
+				let line = first_line + content[..=i].matches('\n').count();
+				result.push_str(&format!("/* {}:{} */", path, line));
+			}
+			// Strings and comments can contain newlines, which are no new
+			// line of code
+			b'"' => i = skip_string(bytes, i, 0),
+			b'r' if !is_ident_byte(bytes, i.wrapping_sub(1))
+				|| (i >= 1
+					&& bytes[i - 1] == b'b'
+					&& !is_ident_byte(bytes, i.wrapping_sub(2))) =>
+			{
+				let hashes =
+					bytes[i + 1..].iter().take_while(|&&b| b == b'#').count();
+				if bytes.get(i + 1 + hashes) == Some(&b'"') {
+					i = skip_string(bytes, i + 1 + hashes, hashes);
+				}
+			}
+			b'\'' => i = skip_char(content, i),
+			b'/' if bytes.get(i + 1) == Some(&b'/') => {
+				while matches!(bytes.get(i + 1), Some(&b) if b != b'\n') {
+					i += 1;
+				}
+			}
+			b'/' if bytes.get(i + 1) == Some(&b'*') => {
+				// Block comments can be nested
+				let mut depth = 0;
+				while i < bytes.len() {
+					if bytes[i..].starts_with(b"/*") {
+						depth += 1;
+						i += 1;
+					} else if bytes[i..].starts_with(b"*/") {
+						depth -= 1;
+						i += 1;
+						if depth == 0 {
+							break;
+						}
 					}
+					i += 1;
 				}
-				panic!(
-					"Reached unknown parsing state (!read_text > !till_end)"
-				);
 			}
+			_ => {}
 		}
-
-		dbg_println!(info, " Rest: {:?}", &cur);
+		i += 1;
 	}
+	if copied < content.len() {
+		result.push_str(&content[copied..]);
+	}
+	result
 }
 
+/// Reads a code block until its end marker, `start` is the offset of the
+/// opening marker within the template.
+///
+/// Also returns if the block ended with `-#>`, the `-` is not part of the
+/// code.
 fn parse_code<'a>(
 	info: &TemplateInfo,
 	input: &'a str,
-) -> Result<(&'a str, String), TemplateError>
+	start: usize,
+) -> Result<(&'a str, String, bool), TemplateError>
 {
 	let mut content = String::new();
 	let mut cur = input;
 
 	loop {
-		match read_code(cur) {
+		match read_code(&info.delimiters, cur) {
 			Ok((rest, done)) => {
 				dbg_print!(info, " take code: {:?}", &done);
-				content.push_str(&done);
+				content.push_str(done);
 				cur = rest;
 
-				if let Ok((rest, _)) = code_end(cur) {
+				if let Ok((rest, _)) = code_end(&info.delimiters, cur) {
 					dbg_print!(info, " code end");
-					return Ok((rest, content));
-				} else if let Ok((rest, _)) = double_code_end(cur) {
+					let trim = content.ends_with('-');
+					if trim {
+						content.pop();
+					}
+					return Ok((rest, content, trim));
+				} else if let Ok((rest, _)) = double_code_end(&info.delimiters, cur)
+				{
 					dbg_print!(info, " double-escape");
-					content.push_str("#>");
+					content.push_str(&info.delimiters.close);
 					cur = rest;
 				} else {
-					panic!("Nothing, i guess?");
+					return Err(TemplateError {
+						index: start,
+						reason: "Could not find the end of the code block"
+							.into(),
+					});
 				}
 			}
 			Err(err) => {
 				dbg_println!(info, "Error at code {:?}", err);
 				return Err(TemplateError {
-					index: 0,
+					index: start,
 					reason: "Unclosed code or expression block".into(),
 				});
 			}
@@ -519,9 +2622,6 @@ fn parse_optimize(data: Vec<TemplatePart>) -> Vec<TemplatePart> {
 							TemplatePartType::Text => {
 								combined.push(Text(tmp_build))
 							}
-							TemplatePartType::Expr => {
-								combined.push(Expr(tmp_build))
-							}
 						}
 					}
 					tmp_build = String::new();
@@ -542,9 +2642,6 @@ fn parse_optimize(data: Vec<TemplatePart>) -> Vec<TemplatePart> {
 							TemplatePartType::Code => {
 								combined.push(Code(tmp_build))
 							}
-							TemplatePartType::Expr => {
-								combined.push(Expr(tmp_build))
-							}
 						}
 					}
 					tmp_build = String::new();
@@ -552,7 +2649,9 @@ fn parse_optimize(data: Vec<TemplatePart>) -> Vec<TemplatePart> {
 				}
 				tmp_build.push_str(&u);
 			}
-			Expr(u) => {
+			// Expressions are never merged, directives keep their position as
+			// they can start or end a function
+			Expr(_) | Directive(_) | Feature(_) => {
 				if !tmp_build.is_empty() {
 					match last_type {
 						TemplatePartType::None => panic!(),
@@ -562,18 +2661,13 @@ fn parse_optimize(data: Vec<TemplatePart>) -> Vec<TemplatePart> {
 						TemplatePartType::Text => {
 							combined.push(Text(tmp_build))
 						}
-						TemplatePartType::Expr => {
-							combined.push(Expr(tmp_build))
-						}
 					}
 				}
 				tmp_build = String::new();
-				last_type = TemplatePartType::Expr;
-				tmp_build.push_str(&u);
-			}
-			Directive(d) => {
-				combined.push(Directive(d));
+				last_type = TemplatePartType::None;
+				combined.push(item);
 			}
+			Comment(_) => {}
 		}
 	}
 	if !tmp_build.is_empty() {
@@ -581,7 +2675,6 @@ fn parse_optimize(data: Vec<TemplatePart>) -> Vec<TemplatePart> {
 			TemplatePartType::None => {}
 			TemplatePartType::Code => combined.push(Code(tmp_build)),
 			TemplatePartType::Text => combined.push(Text(tmp_build)),
-			TemplatePartType::Expr => combined.push(Expr(tmp_build)),
 		}
 	}
 	combined
@@ -589,7 +2682,7 @@ fn parse_optimize(data: Vec<TemplatePart>) -> Vec<TemplatePart> {
 
 /// Applies template directives like 'cleanws' and modifies the input
 /// accordingly.
-fn parse_postprocess(data: &mut Vec<TemplatePart>) {
+fn parse_postprocess(data: &mut [TemplatePart]) {
 	let mut info = TemplateInfo::default();
 	let mut was_b_clean = None;
 	let mut clean_index = 0;
@@ -630,7 +2723,7 @@ fn parse_postprocess(data: &mut Vec<TemplatePart>) {
 
 		let mut res_b = None;
 		if let Text(ref text_b) = tri[2] {
-			if let Ok((_, b_len)) = is_ws_till_newline(&text_b) {
+			if let Ok((_, b_len)) = is_ws_till_newline(text_b) {
 				res_b = Some(b_len);
 			} else {
 				continue;
@@ -659,6 +2752,8 @@ fn parse_postprocess(data: &mut Vec<TemplatePart>) {
 }
 
 fn apply_directive(info: &mut TemplateInfo, directive: &TemplateDirective) {
+	// Directives are checked while parsing, invalid values can only get here
+	// in lenient mode and are ignored.
 	for (key, value) in directive
 		.params
 		.iter()
@@ -666,49 +2761,419 @@ fn apply_directive(info: &mut TemplateInfo, directive: &TemplateDirective) {
 	{
 		match key {
 			("template", "debug") => {
-				info.debug_print = value.parse::<bool>().unwrap()
+				if let Ok(value) = value.parse::<bool>() {
+					info.debug_print = value;
+				}
 			}
 			("template", "cleanws") | ("template", "clean_whitespace") => {
-				info.clean_whitespace = value.parse::<bool>().unwrap()
+				if let Ok(value) = value.parse::<bool>() {
+					info.clean_whitespace = value;
+				}
+			}
+			("template", "lenient") => {
+				if let Ok(value) = value.parse::<bool>() {
+					info.lenient = value;
+				}
 			}
+			// Only one escape function can be active
 			("escape", "function") => {
-				info.print_postprocessor = value.to_string()
+				info.print_postprocessor = value.to_string();
+				info.escape_writer = String::new();
 			}
-			_ => println!(
-				"Unrecognized template parameter \"{}\" in \"{}\"",
-				key.0, key.1
-			),
+			("escape", "writer") => {
+				info.print_postprocessor = String::new();
+				info.escape_writer = value.to_string();
+			}
+			("escape", "mode") => {
+				info.print_postprocessor = String::new();
+				info.escape_writer = if value.is_empty() {
+					String::new()
+				} else {
+					format!("::t4rust::escape::write_{}", value)
+				};
+			}
+			_ => {}
 		}
 	}
 }
 
-// NOM DECLARATIONS ===========================================================
+/// Builds the signature of a template function from its `name` and `params`,
+/// e.g. `item<'t4>(&'t4 self, name: &'t4 str)`, and the start of its body.
+///
+/// The body can be rendered more than once, so parameters which are not
+/// references or primitive types are rebound as references there.
+fn function_signature(
+	directive: &TemplateDirective,
+) -> Result<(String, String), String>
+{
+	let param = |key: &str| {
+		directive
+			.params
+			.iter()
+			.find(|p| p.0 == key)
+			.map_or("", |p| p.1.as_str())
+	};
+	let name = param("name");
+	syn::parse_str::<Ident>(name)
+		.map_err(|_| format!("`{}` is not a valid function name", name))?;
+	let params = param("params");
+	let mut signature =
+		syn::parse_str::<Signature>(&format!("fn f({})", params)).map_err(
+			|e| format!("Invalid function parameters `{}`: {}", params, e),
+		)?;
+	if let Some(FnArg::Receiver(_)) = signature.inputs.first() {
+		return Err("`self` is passed to template functions implicitly".into());
+	}
+	// The returned `Display` borrows the parameters
+	NameElidedLifetimes.visit_signature_mut(&mut signature);
+
+	let mut body = String::new();
+	for input in &mut signature.inputs {
+		if let FnArg::Typed(arg) = input {
+			if is_copy_type(&arg.ty) {
+				continue;
+			}
+			let mut names = BoundNames(Vec::new());
+			names.visit_pat_mut(&mut arg.pat);
+			for name in names.0 {
+				body.push_str(&format!(
+					"#[allow(unused_variables)] let {0} = &{0};\n",
+					name
+				));
+			}
+		}
+	}
+
+	let inputs = &signature.inputs;
+	let signature = format!(
+		"{}<'t4>(&'t4 self, {})",
+		name,
+		mark_user_code(&quote! { #inputs }.to_string())
+	);
+	Ok((signature, body))
+}
+
+/// References and primitive types, which are known to be `Copy`.
+fn is_copy_type(ty: &Type) -> bool {
+	match ty {
+		Type::Reference(reference) => reference.mutability.is_none(),
+		Type::Paren(paren) => is_copy_type(&paren.elem),
+		Type::Group(group) => is_copy_type(&group.elem),
+		Type::Path(path) if path.qself.is_none() => matches!(
+			path.path.get_ident(),
+			Some(ident) if [
+				"bool", "char", "f32", "f64", "i8", "i16", "i32", "i64", "i128",
+				"isize", "u8", "u16", "u32", "u64", "u128", "usize",
+			]
+			.iter()
+			.any(|primitive| ident == primitive)
+		),
+		_ => false,
+	}
+}
+
+/// Collects the names bound by a pattern.
+struct BoundNames(Vec<Ident>);
+
+impl VisitMut for BoundNames {
+	fn visit_pat_ident_mut(&mut self, pat: &mut PatIdent) {
+		self.0.push(pat.ident.clone());
+		visit_mut::visit_pat_ident_mut(self, pat);
+	}
+}
+
+/// Gives all elided lifetimes in the parameters of a template function the
+/// `'t4` lifetime.
+struct NameElidedLifetimes;
+
+impl VisitMut for NameElidedLifetimes {
+	fn visit_type_reference_mut(&mut self, reference: &mut TypeReference) {
+		if reference.lifetime.is_none() {
+			reference.lifetime =
+				Some(Lifetime::new("'t4", reference.and_token.span));
+		}
+		visit_mut::visit_type_reference_mut(self, reference);
+	}
+
+	fn visit_lifetime_mut(&mut self, lifetime: &mut Lifetime) {
+		if lifetime.ident == "_" {
+			lifetime.ident = Ident::new("t4", lifetime.ident.span());
+		}
+	}
+
+	// Elided lifetimes in `fn(&str)` and `Fn(&str)` mean something else
+	fn visit_type_bare_fn_mut(&mut self, _: &mut TypeBareFn) {}
+	fn visit_parenthesized_generic_arguments_mut(
+		&mut self,
+		_: &mut ParenthesizedGenericArguments,
+	) {
+	}
+}
+
+/// Checks that the directive and its parameters are known and have valid
+/// values. In lenient mode problems are only printed as warnings.
+fn check_directive(
+	info: &mut TemplateInfo,
+	directive: &TemplateDirective,
+) -> Result<(), TemplateError>
+{
+	// Enabling lenient mode should already apply to the directive itself
+	if directive.name == "template" {
+		if let Some((_, value)) =
+			directive.params.iter().find(|p| p.0 == "lenient")
+		{
+			info.lenient = value == "true";
+		}
+	}
+
+	let lenient = info.lenient;
+	let report = |reason: String| {
+		if lenient {
+			println!("Warning: {}", reason);
+			Ok(())
+		} else {
+			Err(TemplateError { index: 0, reason })
+		}
+	};
+
+	let params = match DIRECTIVES.iter().find(|d| d.0 == directive.name) {
+		Some(d) => d.1,
+		None => {
+			return report(format!(
+				"Unknown directive \"{}\"{}",
+				directive.name,
+				did_you_mean(&directive.name, DIRECTIVES.iter().map(|d| d.0))
+			));
+		}
+	};
+
+	for (key, value) in &directive.params {
+		let kind = match params.iter().find(|p| p.0 == key) {
+			Some(p) => p.1,
+			None => {
+				report(format!(
+					"Unknown parameter \"{}\" for the \"{}\" directive{}",
+					key,
+					directive.name,
+					did_you_mean(key, params.iter().map(|p| p.0))
+				))?;
+				continue;
+			}
+		};
+
+		if let ParamKind::RuntimePath | ParamKind::Choice(_) = kind {
+			if !value.is_empty() && !info.runtime {
+				report(format!(
+					"The \"{}\" parameter of the \"{}\" directive needs \
+					 the `t4rust` crate, derive `t4rust::Template` instead",
+					key, directive.name
+				))?;
+				continue;
+			}
+		}
+
+		let expected = match kind {
+			ParamKind::Bool if value.parse::<bool>().is_err() => {
+				"`true` or `false`".to_string()
+			}
+			ParamKind::Path | ParamKind::RuntimePath
+				if !value.is_empty()
+					&& syn::parse_str::<syn::Path>(value).is_err() =>
+			{
+				"a rust path or an empty string".into()
+			}
+			ParamKind::Choice(choices)
+				if !value.is_empty() && !choices.contains(&value.as_str()) =>
+			{
+				format!("one of {}", choices.join(", "))
+			}
+			ParamKind::UseTree
+				if syn::parse_str::<syn::UseTree>(value).is_err() =>
+			{
+				"a path which can be used in a `use` item".into()
+			}
+			ParamKind::Type
+				if !value.is_empty()
+					&& syn::parse_str::<syn::Type>(value).is_err() =>
+			{
+				"a rust type or an empty string".into()
+			}
+			_ => continue,
+		};
+		report(format!(
+			"Invalid value \"{}\" for parameter \"{}\" of the \"{}\" \
+			 directive, expected {}",
+			value, key, directive.name, expected
+		))?;
+	}
+	Ok(())
+}
 
-fn expression_start(s: &str) -> IResult<&str, &str> { tag("<#=")(s) }
-fn template_directive_start(s: &str) -> IResult<&str, &str> { tag("<#@")(s) }
-fn read_text(s: &str) -> IResult<&str, &str> { take_until("<#")(s) }
+/// Returns a suggestion for a misspelled name, if a similar candidate exists.
+fn did_you_mean<'a>(
+	name: &str,
+	candidates: impl Iterator<Item = &'a str>,
+) -> String
+{
+	candidates
+		.map(|c| (edit_distance(name, c), c))
+		.filter(|(dist, c)| *dist <= std::cmp::max(1, c.len() / 3))
+		.min_by_key(|(dist, _)| *dist)
+		.map(|(_, c)| format!(", did you mean \"{}\"?", c))
+		.unwrap_or_default()
+}
+
+/// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+	let b: Vec<char> = b.chars().collect();
+	let mut row: Vec<usize> = (0..=b.len()).collect();
+	for (i, ca) in a.chars().enumerate() {
+		let mut diag = row[0];
+		row[0] = i + 1;
+		for (j, cb) in b.iter().enumerate() {
+			let cost = if ca == *cb { diag } else { diag + 1 };
+			diag = row[j + 1];
+			row[j + 1] =
+				std::cmp::min(cost, std::cmp::min(row[j], row[j + 1]) + 1);
+		}
+	}
+	row[b.len()]
+}
+
+// NOM DECLARATIONS ===========================================================
 
-fn code_start(s: &str) -> IResult<&str, &str> {
-	let (s, r) = tag("<#")(s)?;
-	not(tag("<#"))(s)?;
+fn comment_start<'a>(d: &Delimiters, s: &'a str) -> IResult<&'a str, &'a str> {
+	let (s, r) = tag(d.open.as_str())(s)?;
+	let (s, _) = tag("--")(s)?;
 	Ok((s, r))
 }
-fn double_code_start(s: &str) -> IResult<&str, &str> { tag("<#<#")(s) }
+fn expression_start<'a>(d: &Delimiters, s: &'a str) -> IResult<&'a str, bool> {
+	block_start(d, "=", s)
+}
+fn raw_expression_start<'a>(
+	d: &Delimiters,
+	s: &'a str,
+) -> IResult<&'a str, bool>
+{
+	block_start(d, "!", s)
+}
+fn debug_expression_start<'a>(
+	d: &Delimiters,
+	s: &'a str,
+) -> IResult<&'a str, bool>
+{
+	block_start(d, "?", s)
+}
+fn feature_start<'a>(d: &Delimiters, s: &'a str) -> IResult<&'a str, bool> {
+	block_start(d, "+", s)
+}
+fn template_directive_start<'a>(
+	d: &Delimiters,
+	s: &'a str,
+) -> IResult<&'a str, bool>
+{
+	block_start(d, "@", s)
+}
+fn read_text<'a>(d: &Delimiters, s: &'a str) -> IResult<&'a str, &'a str> {
+	take_until(d.open.as_str())(s)
+}
+
+fn code_start<'a>(d: &Delimiters, s: &'a str) -> IResult<&'a str, bool> {
+	let (s, _) = tag(d.open.as_str())(s)?;
+	not(tag(d.open.as_str()))(s)?;
+	let (s, trim) = opt(tag("-"))(s)?;
+	Ok((s, trim.is_some()))
+}
+
+/// Matches the start of a block of the given kind, returns if it has a `-` to
+/// trim the whitespace in front of it.
+fn block_start<'a>(
+	d: &Delimiters,
+	kind: &str,
+	s: &'a str,
+) -> IResult<&'a str, bool>
+{
+	let (s, _) = tag(d.open.as_str())(s)?;
+	let (s, trim) = opt(tag("-"))(s)?;
+	let (s, _) = tag(kind)(s)?;
+	Ok((s, trim.is_some()))
+}
+fn double_code_start<'a>(
+	d: &Delimiters,
+	s: &'a str,
+) -> IResult<&'a str, &'a str>
+{
+	let (s, r) = tag(d.open.as_str())(s)?;
+	tag(d.open.as_str())(s)?;
+	Ok((&s[d.open.len()..], r))
+}
 
-fn code_end(s: &str) -> IResult<&str, &str> {
-	let (s, r) = tag("#>")(s)?;
-	not(tag("#>"))(s)?;
+fn code_end<'a>(d: &Delimiters, s: &'a str) -> IResult<&'a str, &'a str> {
+	let (s, r) = tag(d.close.as_str())(s)?;
+	not(tag(d.close.as_str()))(s)?;
 	Ok((s, r))
 }
-fn double_code_end(s: &str) -> IResult<&str, &str> { tag("#>#>")(s) }
+fn double_code_end<'a>(
+	d: &Delimiters,
+	s: &'a str,
+) -> IResult<&'a str, &'a str>
+{
+	let (s, r) = tag(d.close.as_str())(s)?;
+	tag(d.close.as_str())(s)?;
+	Ok((&s[d.close.len()..], r))
+}
 
-fn read_code(s: &str) -> IResult<&str, &str> { take_until("#>")(s) }
+fn read_code<'a>(d: &Delimiters, s: &'a str) -> IResult<&'a str, &'a str> {
+	take_until(d.close.as_str())(s)
+}
+
+fn read_comment<'a>(d: &Delimiters, s: &'a str) -> IResult<&'a str, &'a str> {
+	let end = format!("--{}", d.close);
+	let (s, r) = take_until(end.as_str())(s)?;
+	Ok((&s[end.len()..], r))
+}
+
+/// Reads the content of a raw block up to the matching `endraw` directive.
+/// Returns the content, the rest after the directive and its trim markers.
+fn read_raw<'a>(
+	d: &Delimiters,
+	s: &'a str,
+) -> Option<(&'a str, &'a str, (bool, bool))>
+{
+	let mut offset = 0;
+	loop {
+		let (_, skipped) = read_text(d, &s[offset..]).ok()?;
+		let pos = offset + skipped.len();
+		if let Ok((rest, trims)) = endraw_directive(d, &s[pos..]) {
+			return Some((&s[..pos], rest, trims));
+		}
+		offset = pos + d.open.len();
+	}
+}
+
+fn endraw_directive<'a>(
+	d: &Delimiters,
+	s: &'a str,
+) -> IResult<&'a str, (bool, bool)>
+{
+	let (s, trim_before) = block_start(d, "@", s)?;
+	let (s, _) = tuple((space0, tag("endraw"), space0))(s)?;
+	let (s, trim_after) = opt(tag("-"))(s)?;
+	let (s, _) = tag(d.close.as_str())(s)?;
+	Ok((s, (trim_before, trim_after.is_some())))
+}
 
 fn till_end(s: &str) -> IResult<&str, &str> { take_while(|_| true)(s) }
 
 fn parse_directive(s: &str) -> IResult<&str, TemplateDirective> {
 	map(
-		tuple((space0, alphanumeric1, many0(parse_directive_param), at_end)),
+		tuple((
+			space0,
+			alphanumeric1,
+			many0(parse_directive_param),
+			space0,
+			at_end,
+		)),
 		|t| TemplateDirective { name: t.1.to_string(), params: t.2 },
 	)(s)
 }
@@ -746,7 +3211,7 @@ fn is_ws_till_newline(s: &str) -> IResult<&str, (usize, usize)> {
 fn tag_transform<'a>(
 	s: &'a str,
 	t: &'a str,
-) -> impl Fn(&'a str) -> IResult<&str, &str>
+) -> impl Fn(&'a str) -> IResult<&'a str, &'a str>
 {
 	move |i: &'a str| {
 		let (r, _) = tag(s)(i)?;
@@ -759,29 +3224,156 @@ fn tag_transform<'a>(
 #[derive(Debug)]
 struct TemplateError {
 	reason: String,
+	/// Byte offset into the template where the error occured
 	index: usize,
 }
 
+impl TemplateError {
+	/// Formats the error with the position and the affected line of the
+	/// template, similar to rustc diagnostics.
+	fn render(&self, name: &str, source: &str) -> String {
+		let index = std::cmp::min(self.index, source.len());
+		let line_start = source[..index].rfind('\n').map_or(0, |i| i + 1);
+		let line_end =
+			source[index..].find('\n').map_or(source.len(), |i| index + i);
+		let line_nr = source[..index].matches('\n').count() + 1;
+		let line = source[line_start..line_end].trim_end_matches('\r');
+		let before = &source[line_start..index];
+		// Keep tabs so the caret lines up with the code above
+		let indent: String = before
+			.chars()
+			.map(|c| if c == '\t' { '\t' } else { ' ' })
+			.collect();
+		let gutter = " ".repeat(line_nr.to_string().len());
+
+		format!(
+			"{reason}\n{gutter}--> {name}:{line_nr}:{col}\n{gutter} |\n\
+			 {line_nr} | {line}\n{gutter} | {indent}^",
+			reason = self.reason,
+			gutter = gutter,
+			name = name,
+			line_nr = line_nr,
+			col = before.chars().count() + 1,
+			line = line,
+			indent = indent,
+		)
+	}
+}
+
+/// Template attributes of the type or of an enum variant.
+struct TemplateAttributes {
+	/// The default template, which implements `Display`
+	source: Option<TemplateSource>,
+	/// Templates from `#[TemplateNamed]`, which get their own methods
+	named: Vec<(String, TemplateSource)>,
+	/// Span of `#[TemplateBindFields]`
+	bind_fields: Option<proc_macro2::Span>,
+}
+
+/// Where the template text comes from.
+enum TemplateSource {
+	/// `#[TemplatePath = "..."]`, a file relative to the crate root
+	Path(LitStr),
+	/// `#[TemplateSource = "..."]`, the template text itself
+	Inline(LitStr),
+}
+
+#[derive(Clone, Copy)]
+enum ParamKind {
+	Bool,
+	Text,
+	/// A rust path, or empty
+	Path,
+	/// A rust path, or empty. Needs the `t4rust` crate.
+	RuntimePath,
+	/// One of the values, or empty. Needs the `t4rust` crate.
+	Choice(&'static [&'static str]),
+	/// What follows after `use`, e.g. `std::fmt::{self, Write}`
+	UseTree,
+	/// A rust type, or empty
+	Type,
+}
+
+/// Escape functions in `t4rust::escape` for the escape directive
+const ESCAPE_MODES: &[&str] =
+	&["html", "xml", "json", "url", "shell", "csv", "rust"];
+
+/// All known directives with their parameters
+const DIRECTIVES: &[(&str, &[(&str, ParamKind)])] = &[
+	(
+		"template",
+		&[
+			("debug", ParamKind::Bool),
+			("cleanws", ParamKind::Bool),
+			("clean_whitespace", ParamKind::Bool),
+			("lenient", ParamKind::Bool),
+		],
+	),
+	(
+		"escape",
+		&[
+			("function", ParamKind::Path),
+			("writer", ParamKind::RuntimePath),
+			("mode", ParamKind::Choice(ESCAPE_MODES)),
+		],
+	),
+	("include", &[("file", ParamKind::Text)]),
+	("raw", &[]),
+	("endraw", &[]),
+	("extends", &[("file", ParamKind::Text)]),
+	("block", &[("name", ParamKind::Text)]),
+	("endblock", &[]),
+	("function", &[("name", ParamKind::Text), ("params", ParamKind::Text)]),
+	("endfunction", &[]),
+	("import", &[("namespace", ParamKind::UseTree)]),
+	("parameter", &[("name", ParamKind::Text), ("type", ParamKind::Type)]),
+];
+
 #[derive(Debug)]
 struct TemplateDirective {
 	name: String,
 	params: Vec<(String, String)>,
 }
 
+#[derive(Debug)]
+struct Expression {
+	code: String,
+	/// Format spec after the `:`, empty for `{}`
+	format: String,
+	/// Skip the escape function
+	raw: bool,
+}
+
+impl Expression {
+	fn format_string(&self) -> String {
+		if self.format.is_empty() {
+			"{}".into()
+		} else {
+			format!("{{:{}}}", self.format)
+		}
+	}
+}
+
 #[derive(Debug)]
 enum TemplatePart {
 	Text(String),
 	Code(String),
-	Expr(String),
+	Expr(Expression),
 	Directive(TemplateDirective),
+	/// Removed after whitespace cleaning
+	Comment(String),
+	/// Items from a `<#+ #>` block, which are put next to the generated code
+	Feature(String),
 }
 
 impl TemplatePart {
 	fn is_text(&self) -> bool { matches!(self, Text(_)) }
 
-	/// Whitespace should only be trimmed for code and directive blocks, we want to keep it for
-	/// expressions.
-	fn should_trim_whitespace(&self) -> bool { matches!(self, Code(_) | Directive(_)) }
+	/// Whitespace should only be trimmed for code, directive, comment and feature blocks, we want to keep
+	/// it for expressions.
+	fn should_trim_whitespace(&self) -> bool {
+		matches!(self, Code(_) | Directive(_) | Comment(_) | Feature(_))
+	}
 }
 
 #[derive(PartialEq)]
@@ -789,7 +3381,30 @@ enum TemplatePartType {
 	None,
 	Code,
 	Text,
-	Expr,
+}
+
+/// The markers which start and end blocks
+#[derive(Clone, Debug)]
+struct Delimiters {
+	open: String,
+	close: String,
+}
+
+impl Default for Delimiters {
+	fn default() -> Self { Self { open: "<#".into(), close: "#>".into() } }
+}
+
+impl Delimiters {
+	/// Parses a pair like `"{% %}"`.
+	fn parse(s: &str) -> Option<Self> {
+		let mut parts = s.split_whitespace();
+		match (parts.next(), parts.next(), parts.next()) {
+			(Some(open), Some(close), None) => {
+				Some(Self { open: open.into(), close: close.into() })
+			}
+			_ => None,
+		}
+	}
 }
 
 #[derive(Debug)]
@@ -797,14 +3412,607 @@ struct TemplateInfo {
 	debug_print: bool,
 	clean_whitespace: bool,
 	print_postprocessor: String,
+	/// Escape function for `t4rust::EscapeWriter`, replaces the
+	/// `print_postprocessor`
+	escape_writer: String,
+	/// Only warn about unknown directives instead of failing
+	lenient: bool,
+	/// Derived through the `t4rust` crate, so its traits and escape
+	/// functions can be used
+	runtime: bool,
+	delimiters: Delimiters,
+	/// Files which are currently being parsed, the innermost one is last
+	include_stack: Vec<PathBuf>,
+	/// All files the template is built from
+	dependencies: Vec<PathBuf>,
 }
 
 impl TemplateInfo {
+	/// Settings from the attributes of an enum, which also apply to its
+	/// variants.
+	fn inherit(&self) -> Self {
+		Self {
+			debug_print: self.debug_print,
+			runtime: self.runtime,
+			delimiters: self.delimiters.clone(),
+			..Self::default()
+		}
+	}
+
 	fn default() -> Self {
 		Self {
 			debug_print: false,
 			clean_whitespace: false,
 			print_postprocessor: "".into(),
+			escape_writer: "".into(),
+			lenient: false,
+			runtime: false,
+			delimiters: Delimiters::default(),
+			include_stack: Vec::new(),
+			dependencies: Vec::new(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn directive(name: &str, params: &[(&str, &str)]) -> TemplateDirective {
+		TemplateDirective {
+			name: name.into(),
+			params: params
+				.iter()
+				.map(|(k, v)| (k.to_string(), v.to_string()))
+				.collect(),
+		}
+	}
+
+	fn check(info: &mut TemplateInfo, dir: &TemplateDirective) -> String {
+		match check_directive(info, dir) {
+			Ok(()) => String::new(),
+			Err(e) => e.reason,
+		}
+	}
+
+	#[test]
+	fn include_cycle() {
+		let dir = std::env::temp_dir()
+			.join(format!("t4rust-include-cycle-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let dir = dir.canonicalize().unwrap();
+		let file = |name: &str, content: &str| {
+			let path = dir.join(name);
+			std::fs::write(&path, content).unwrap();
+			path
+		};
+		let a = file("a.tt", "A <#@ include file=\"b.tt\" #>");
+		let b = file("b.tt", "B <#@ include file=\"a.tt\" #>");
+		let c = file("c.tt", "<#@ extends file=\"c.tt\" #>");
+
+		let parse = |path: &PathBuf| {
+			let mut info = TemplateInfo::default();
+			info.include_stack.push(path.clone());
+			let content = std::fs::read_to_string(path).unwrap();
+			parse_all(&mut info, &content).unwrap_err()
+		};
+		let include = parse(&a);
+		let inheritance = parse(&c);
+		std::fs::remove_dir_all(&dir).unwrap();
+
+		let (a, b, c) = (display_path(&a), display_path(&b), display_path(&c));
+		assert_eq!(include.index, 2);
+		let mut lines = include.reason.lines();
+		assert_eq!(
+			lines.next(),
+			Some(
+				format!("Include cycle detected: {} -> {} -> {}", a, b, a)
+					.as_str()
+			)
+		);
+		assert_eq!(lines.next(), Some(format!(" --> {}:1:3", b).as_str()));
+		assert_eq!(lines.last(), Some("included from"));
+		assert_eq!(
+			inheritance.reason,
+			format!("Inheritance cycle detected: {} -> {}", c, c)
+		);
+	}
+
+	#[test]
+	fn extends_outside_blocks() {
+		let dir = std::env::temp_dir()
+			.join(format!("t4rust-extends-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let base = "<#@ block name=\"a\" #><#@ endblock #>";
+		std::fs::write(dir.join("base.tt"), base).unwrap();
+		let parse = |content: &str| {
+			let mut info = TemplateInfo::default();
+			info.include_stack.push(dir.join("page.tt"));
+			parse_all(&mut info, content)
+		};
+		let page = "\n<#@ extends file=\"base.tt\" #>\n<#-- x --#>\n\
+		            <#@ block name=\"a\" #>A<#@ endblock #>\n";
+		let ok = parse(page);
+		let code = parse(&format!("{}<# let x = 1; #>", page));
+		let expr = parse(&format!("{}<#= 1 #>", page));
+		let text = parse(&format!("{}  Text", page));
+		let before = parse(&format!("Text{}", page));
+		std::fs::remove_dir_all(&dir).unwrap();
+
+		assert!(ok.is_ok());
+		let end = page.len();
+		for (error, index) in
+			[(code, end), (expr, end), (text, end + 2), (before, 0)]
+		{
+			let error = error.unwrap_err();
+			assert_eq!(error.index, index);
+			assert!(error.reason.starts_with("Only directives"));
+		}
+	}
+
+	fn render(index: usize, source: &str) -> String {
+		let error = TemplateError { index, reason: "Oops".into() };
+		error.render("a.tt", source)
+	}
+
+	#[test]
+	fn render_error() {
+		assert_eq!(
+			render(6, "Hello <#= x"),
+			"Oops\n --> a.tt:1:7\n  |\n1 | Hello <#= x\n  |       ^"
+		);
+		// The gutter grows with the line number
+		let source = "\n".repeat(9) + "x";
+		assert_eq!(
+			render(9, &source),
+			"Oops\n  --> a.tt:10:1\n   |\n10 | x\n   | ^"
+		);
+	}
+
+	#[test]
+	fn render_error_keeps_tabs() {
+		assert_eq!(
+			render(4, "\tab\t<#"),
+			"Oops\n --> a.tt:1:5\n  |\n1 | \tab\t<#\n  | \t  \t^"
+		);
+	}
+
+	#[test]
+	fn render_error_crlf() {
+		let source = "a\r\n <#\r\nb";
+		assert_eq!(
+			render(4, source),
+			"Oops\n --> a.tt:2:2\n  |\n2 |  <#\n  |  ^"
+		);
+	}
+
+	#[test]
+	fn render_error_multibyte() {
+		// The column and the caret count characters, not bytes
+		assert_eq!(
+			render(5, "äö <#"),
+			"Oops\n --> a.tt:1:4\n  |\n1 | äö <#\n  |    ^"
+		);
+	}
+
+	#[test]
+	fn render_error_end_of_input() {
+		assert_eq!(
+			render(3, "<#="),
+			"Oops\n --> a.tt:1:4\n  |\n1 | <#=\n  |    ^"
+		);
+		assert_eq!(
+			render(3, "ab\n"),
+			"Oops\n --> a.tt:2:1\n  |\n2 | \n  | ^"
+		);
+		// Out of range indices point to the end
+		assert_eq!(render(100, "ab"), render(2, "ab"));
+	}
+
+	#[test]
+	fn split_format_spec_at_spaced_colon() {
+		assert_eq!(
+			split_format_spec("self.price :>10.2"),
+			("self.price ", ">10.2")
+		);
+		assert_eq!(split_format_spec("x\t:#x "), ("x\t", "#x"));
+		assert_eq!(split_format_spec("x :"), ("x ", ""));
+		assert_eq!(split_format_spec("a::b"), ("a::b", ""));
+		assert_eq!(split_format_spec("a :: b"), ("a :: b", ""));
+		assert_eq!(split_format_spec("a :: b :?"), ("a :: b ", "?"));
+		assert_eq!(split_format_spec("S { a: 1 }.a"), ("S { a: 1 }.a", ""));
+	}
+
+	#[test]
+	fn split_format_spec_skips_literals() {
+		for code in &[
+			"'\\'' == c",
+			"':' == c",
+			"' ' == c",
+			"'\\\\' == c",
+			"\" :x\"",
+			"\"\\\" :x\"",
+			"r\" :x\"",
+			"r#\" :x \"#",
+			"r##\" \"# :x \"##",
+			"(|x: u32| x)(1)",
+			"[a :x]",
+			"f::<'a>(x)",
+		] {
+			assert_eq!(split_format_spec(code), (*code, ""), "{}", code);
+		}
+		assert_eq!(split_format_spec("':' :?"), ("':' ", "?"));
+		assert_eq!(
+			split_format_spec("r#\" :x \"# :?"),
+			("r#\" :x \"# ", "?")
+		);
+		// Lifetimes have no closing quote
+		assert_eq!(split_format_spec("x::<'a> :?"), ("x::<'a> ", "?"));
+		// An `r` at the end of an identifier does not start a raw string
+		assert_eq!(split_format_spec("bar :?"), ("bar ", "?"));
+	}
+
+	#[test]
+	fn skip_string_finds_end() {
+		assert_eq!(skip_string(b"\"abc\" x", 0, 0), 4);
+		assert_eq!(skip_string(b"\"a\\\"b\" x", 0, 0), 5);
+		// Raw strings end at the last `#`
+		assert_eq!(skip_string(b"#\"a\"b\"# x", 1, 1), 6);
+		// Unclosed strings end at the input
+		assert_eq!(skip_string(b"\"abc", 0, 0), 4);
+	}
+
+	#[test]
+	fn check_format_spec_valid() {
+		for spec in &[
+			"", "?", "#?", "x", "#X", "08.3", ">10", "*^+#010.2e", "x<5",
+			"ä>5", "width$", "<width$.prec$", ".prec$x", "_w$", "-5",
+		] {
+			assert_eq!(check_format_spec(spec), Ok(()), "{}", spec);
+		}
+	}
+
+	#[test]
+	fn check_format_spec_invalid() {
+		let check = |spec| check_format_spec(spec).unwrap_err();
+		assert_eq!(check("{>5"), "`{` and `}` can not be used as fill");
+		assert_eq!(check("}<5"), "`{` and `}` can not be used as fill");
+		assert_eq!(check("1$"), "positional argument `1$` is not supported");
+		assert_eq!(check(">0$"), "positional argument `0$` is not supported");
+		assert_eq!(check(".2$"), "positional argument `2$` is not supported");
+		assert_eq!(check(".*"), "`.*` is not supported, use a named argument");
+		assert_eq!(check("5."), "expected a precision after `.`");
+		assert_eq!(check("y"), "unexpected `y`");
+		assert_eq!(check("10 x"), "unexpected ` x`");
+	}
+
+	#[test]
+	fn type_to_string_like_written() {
+		for ty in &[
+			"String",
+			"&'static str",
+			"&'a mut Vec<u8>",
+			"std::collections::HashMap<String, Vec<(u8, i32)>>",
+			"[u8; 4]",
+			"*const [T]",
+			"Box<dyn Fn(&str) -> String + Send + 'static>",
+			"Option<fn()>",
+			"impl Iterator<Item = u8>",
+			"Foo<{ N }>",
+		] {
+			let parsed: Type = syn::parse_str(ty).unwrap();
+			assert_eq!(type_to_string(quote! { #parsed }), *ty);
+		}
+	}
+
+	#[test]
+	fn mark_source_lines_of_code() {
+		let path = manifest_dir().unwrap().canonicalize().unwrap();
+		let path = path.join("tests").join("page.tt");
+		let file = display_path(&path);
+		let mut info = TemplateInfo::default();
+		info.include_stack.push(path);
+		let template = "a\n\tä <# x(); #><#=  y\n\t.z :>3 #>";
+		let parts = parse_all(&mut info, template).unwrap();
+		let code: Vec<_> = parts
+			.iter()
+			.filter_map(|part| match part {
+				Code(code) => Some(code.clone()),
+				Expr(expr) => Some(expr.code.clone()),
+				_ => None,
+			})
+			.collect();
+		assert_eq!(code, [
+			format!("/* {}:2:6 */ x(); ", file),
+			format!("/* {0}:2:17 */  y\n/* {0}:3 */\t.z ", file),
+		]);
+
+		// Newlines in strings and comments start no new line of code, but
+		// are counted
+		let code = "a(\"\n\", r#\"\n\"#, b'\\n', '\"');\n\
+			// \"\n\
+			/* /* \n */ \" */\n\
+			b(br\"\\\"\n\");";
+		let marked = mark_source_lines(&info, code, code, code);
+		assert_eq!(
+			marked,
+			format!(
+				"/* {0}:1:1 */a(\"\n\", r#\"\n\"#, b'\\n', '\"');\n\
+				 /* {0}:4 */// \"\n\
+				 /* {0}:5 */\
+				 /* /* \n */ \" */\n\
+				 /* {0}:7 */\
+				 b(br\"\\\"\n\");",
+				file
+			)
+		);
+
+		// Errors in inline templates point to the attribute instead
+		let parts = parse_all(&mut TemplateInfo::default(), "<# x(); #>");
+		assert!(matches!(
+			&parts.unwrap()[..],
+			[Text(_), Code(code)] if code == " x(); "
+		));
+	}
+
+	#[test]
+	fn template_file_code_is_included_with_locations() {
+		let lit = |value| LitStr::new(value, proc_macro2::Span::call_site());
+		let source = TemplateSource::Path(lit("tests/simple_template.tt"));
+		let info = TemplateInfo::default();
+		let template = compile_template(&source, info, String::new()).unwrap();
+		let tokens = template.tokens.to_string();
+		let path = tokens
+			.split("include ! (\"")
+			.nth(1)
+			.and_then(|rest| rest.split('"').next())
+			.unwrap_or_else(|| panic!("No include in {}", tokens));
+		let code = std::fs::read_to_string(path).unwrap();
+		assert!(
+			code.contains("/* tests/simple_template.tt:1:9 */ self.text"),
+			"{}",
+			code
+		);
+
+		// Inline templates point errors to the attribute instead
+		let source = TemplateSource::Inline(lit("<#= self.text #>"));
+		let info = TemplateInfo::default();
+		let template = compile_template(&source, info, String::new()).unwrap();
+		assert!(!template.tokens.to_string().contains("include !"));
+	}
+
+	#[test]
+	fn named_template_names() {
+		let parse = |attrs: Vec<Attribute>| {
+			parse_attributes(&attrs, &mut TemplateInfo::default())
+				.map(|_| ())
+				.map_err(|e| e.to_string())
+		};
+		assert_eq!(
+			parse(vec![
+				parse_quote!(#[TemplateNamed(name = "x", source = "")]),
+				parse_quote!(#[TemplateNamed(name = "x_into", source = "")]),
+			]),
+			Err("The templates `x` and `x_into` would both have a \
+			     `render_x_into` method, choose another name"
+				.into())
+		);
+		assert_eq!(
+			parse(vec![
+				parse_quote!(#[TemplateNamed(name = "x_into", source = "")]),
+				parse_quote!(#[TemplateNamed(name = "x", source = "")]),
+			]),
+			Err("The templates `x_into` and `x` would both have a \
+			     `render_x_into` method, choose another name"
+				.into())
+		);
+		assert_eq!(
+			parse(vec![
+				parse_quote!(#[TemplateNamed(name = "x", source = "")]),
+				parse_quote!(#[TemplateNamed(name = "x", source = "")]),
+			]),
+			Err("There is already a template called `x`".into())
+		);
+		assert_eq!(
+			parse(vec![parse_quote!(#[TemplateNamed(name = "X", source = "")])]),
+			Err("The name has to be lowercase snake case, e.g. \"html\" or \
+			     \"list_item\""
+				.into())
+		);
+		assert_eq!(
+			parse(vec![
+				parse_quote!(#[TemplateNamed(name = "x", source = "")]),
+				parse_quote!(#[TemplateNamed(name = "into", source = "")]),
+				parse_quote!(#[TemplateNamed(name = "x_in", source = "")]),
+			]),
+			Ok(())
+		);
+	}
+
+	#[test]
+	fn conflicting_functions() {
+		let input: DeriveInput = parse_quote! {
+			enum Message {
+				#[TemplateSource = "<#@ function name=\"f\" #>A<#@ endfunction #>"]
+				A,
+				#[TemplateSource = "<#@ function name=\"f\" #>B<#@ endfunction #>"]
+				B,
+			}
+		};
+		assert_eq!(
+			derive_template(&input, false).unwrap_err().to_string(),
+			"The function `f` is defined differently in the template of \
+			 `Message::A` and the template of `Message::B`, functions with the \
+			 same name need the same code and imports"
+		);
+	}
+
+	#[test]
+	fn conflicting_feature_items() {
+		let input: DeriveInput = parse_quote! {
+			#[TemplateSource = "<#+ struct Tag; #>"]
+			#[TemplateNamed(name = "a", source = "<#+ struct Tag(u8); #>")]
+			#[TemplateNamed(name = "b", source = "<#+ fn f(&self) {} #>")]
+			#[TemplateNamed(
+				name = "c",
+				source = "<#@ function name=\"f\" #><#@ endfunction #>"
+			)]
+			struct Page;
+		};
+		assert_eq!(
+			derive_template(&input, false).unwrap_err().to_string(),
+			"`Tag` is defined in the template of `Page` and again in a `<#+ #>` \
+			 block of the `a` template"
+		);
+
+		let mut input = input;
+		input.attrs.remove(1);
+		assert_eq!(
+			derive_template(&input, false).unwrap_err().to_string(),
+			"`f` is defined in the `c` template and again in a `<#+ #>` block \
+			 of the `b` template"
+		);
+	}
+
+	#[test]
+	fn check_directive_accepts_valid_directives() {
+		let mut info = TemplateInfo::default();
+		for dir in &[
+			directive("template", &[("cleanws", "true"), ("debug", "false")]),
+			directive("escape", &[("function", "crate::escape_html")]),
+			directive("escape", &[("function", "")]),
+			directive("include", &[("file", "header.tt")]),
+			directive("import", &[("namespace", "std::fmt::{self, Write}")]),
+			directive("parameter", &[("name", "items"), ("type", "Vec<u8>")]),
+			directive("parameter", &[("name", "items"), ("type", "")]),
+			directive("endraw", &[]),
+		] {
+			assert_eq!(check(&mut info, dir), "", "{:?}", dir);
+		}
+	}
+
+	#[test]
+	fn check_directive_reports_unknown_names() {
+		let mut info = TemplateInfo::default();
+		assert_eq!(
+			check(&mut info, &directive("tempalte", &[])),
+			"Unknown directive \"tempalte\", did you mean \"template\"?"
+		);
+		assert_eq!(
+			check(&mut info, &directive("foo", &[])),
+			"Unknown directive \"foo\""
+		);
+		assert_eq!(
+			check(&mut info, &directive("template", &[("clenws", "true")])),
+			"Unknown parameter \"clenws\" for the \"template\" directive, \
+			 did you mean \"cleanws\"?"
+		);
+	}
+
+	#[test]
+	fn check_directive_reports_invalid_values() {
+		let mut info = TemplateInfo::default();
+		assert_eq!(
+			check(&mut info, &directive("template", &[("cleanws", "yes")])),
+			"Invalid value \"yes\" for parameter \"cleanws\" of the \
+			 \"template\" directive, expected `true` or `false`"
+		);
+		let invalid = [
+			directive("escape", &[("function", "escape html")]),
+			directive("import", &[("namespace", "")]),
+			directive("import", &[("namespace", "std::{")]),
+			directive("parameter", &[("name", "a"), ("type", "Vec<")]),
+		];
+		for dir in &invalid {
+			assert!(
+				check(&mut info, dir).starts_with("Invalid value"),
+				"{:?}",
+				dir
+			);
 		}
 	}
+
+	#[test]
+	fn check_directive_runtime_parameters() {
+		let mut info = TemplateInfo::default();
+		let mode = directive("escape", &[("mode", "html")]);
+		assert_eq!(
+			check(&mut info, &mode),
+			"The \"mode\" parameter of the \"escape\" directive needs the \
+			 `t4rust` crate, derive `t4rust::Template` instead"
+		);
+		// Disabling is always allowed
+		assert_eq!(
+			check(&mut info, &directive("escape", &[("writer", "")])),
+			""
+		);
+
+		info.runtime = true;
+		assert_eq!(check(&mut info, &mode), "");
+		assert_eq!(
+			check(&mut info, &directive("escape", &[("writer", "a::b")])),
+			""
+		);
+		assert_eq!(
+			check(&mut info, &directive("escape", &[("mode", "htm")])),
+			format!(
+				"Invalid value \"htm\" for parameter \"mode\" of the \
+				 \"escape\" directive, expected one of {}",
+				ESCAPE_MODES.join(", ")
+			)
+		);
+	}
+
+	#[test]
+	fn check_directive_lenient() {
+		let mut info = TemplateInfo::default();
+		// Applies to the directive which enables it
+		let dir = directive("template", &[("lenient", "true"), ("x", "")]);
+		assert_eq!(check(&mut info, &dir), "");
+		assert!(info.lenient);
+		assert_eq!(check(&mut info, &directive("unknown", &[])), "");
+		assert_eq!(
+			check(&mut info, &directive("template", &[("debug", "1")])),
+			""
+		);
+
+		let dir = directive("template", &[("lenient", "false")]);
+		assert_eq!(check(&mut info, &dir), "");
+		assert_ne!(check(&mut info, &directive("unknown", &[])), "");
+	}
+
+	#[test]
+	fn did_you_mean_suggestions() {
+		let names = || DIRECTIVES.iter().map(|d| d.0);
+		assert_eq!(
+			did_you_mean("tempalte", names()),
+			", did you mean \"template\"?"
+		);
+		assert_eq!(
+			did_you_mean("endblok", names()),
+			", did you mean \"endblock\"?"
+		);
+		assert_eq!(did_you_mean("rwa", names()), "");
+		assert_eq!(did_you_mean("rw", names()), ", did you mean \"raw\"?");
+		assert_eq!(did_you_mean("something", names()), "");
+		assert_eq!(did_you_mean("x", std::iter::empty()), "");
+		// The closest candidate wins
+		assert_eq!(
+			did_you_mean("blocks", ["endblock", "block"].iter().copied()),
+			", did you mean \"block\"?"
+		);
+	}
+
+	#[test]
+	fn edit_distance_counts_changes() {
+		assert_eq!(edit_distance("", ""), 0);
+		assert_eq!(edit_distance("raw", "raw"), 0);
+		assert_eq!(edit_distance("", "raw"), 3);
+		assert_eq!(edit_distance("raw", ""), 3);
+		assert_eq!(edit_distance("kitten", "sitting"), 3);
+		assert_eq!(edit_distance("tempalte", "template"), 2);
+		assert_eq!(edit_distance("block", "endblock"), 3);
+		// Characters, not bytes
+		assert_eq!(edit_distance("blöck", "block"), 1);
+	}
 }
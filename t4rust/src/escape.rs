@@ -0,0 +1,157 @@
+//! Escape functions for common output formats.
+//!
+//! They can be used with the `escape` directive by name, e.g.
+//! `<#@ escape mode="html" #>`, or by path like any other escape function:
+//! `<#@ escape writer="t4rust::escape::write_html" #>`.
+//!
+//! Every format has two functions, one which writes the escaped text into a
+//! [`fmt::Write`] (`write_html`), which is used by the escape directive and
+//! does not allocate, and one which returns a new `String` (`html`).
+//!
+//! Functions for formats with quoted strings (`json`, `shell`, `csv`, `rust`)
+//! only escape the content, the quotes need to be written in the template:
+//! ```text
+//! <#@ escape mode="json" #>
+//! { "name": "<#= self.name #>" }
+//! ```
+
+use std::fmt;
+
+/// Escapes `&`, `<`, `>`, `"` and `'` for html text and attribute values.
+pub fn write_html(out: &mut dyn fmt::Write, s: &str) -> fmt::Result {
+	replace_chars(out, s, |c| match c {
+		'&' => Some("&amp;"),
+		'<' => Some("&lt;"),
+		'>' => Some("&gt;"),
+		'"' => Some("&quot;"),
+		'\'' => Some("&#x27;"),
+		_ => None,
+	})
+}
+
+/// Escapes `&`, `<`, `>`, `"` and `'` for xml text and attribute values.
+pub fn write_xml(out: &mut dyn fmt::Write, s: &str) -> fmt::Result {
+	replace_chars(out, s, |c| match c {
+		'&' => Some("&amp;"),
+		'<' => Some("&lt;"),
+		'>' => Some("&gt;"),
+		'"' => Some("&quot;"),
+		'\'' => Some("&apos;"),
+		_ => None,
+	})
+}
+
+/// Escapes the content of a json string.
+pub fn write_json(out: &mut dyn fmt::Write, s: &str) -> fmt::Result {
+	for c in s.chars() {
+		match c {
+			'"' => out.write_str("\\\"")?,
+			'\\' => out.write_str("\\\\")?,
+			'\n' => out.write_str("\\n")?,
+			'\r' => out.write_str("\\r")?,
+			'\t' => out.write_str("\\t")?,
+			'\u{8}' => out.write_str("\\b")?,
+			'\u{c}' => out.write_str("\\f")?,
+			c if c < ' ' => write!(out, "\\u{:04x}", c as u32)?,
+			c => out.write_char(c)?,
+		}
+	}
+	Ok(())
+}
+
+/// Percent-encodes everything except unreserved characters
+/// (`A-Z a-z 0-9 - _ . ~`), so the result can be used as a path segment or
+/// query parameter.
+pub fn write_url(out: &mut dyn fmt::Write, s: &str) -> fmt::Result {
+	for b in s.bytes() {
+		match b {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.'
+			| b'~' => out.write_char(b as char)?,
+			b => write!(out, "%{:02X}", b)?,
+		}
+	}
+	Ok(())
+}
+
+/// Escapes the content of a single-quoted shell string.
+pub fn write_shell(out: &mut dyn fmt::Write, s: &str) -> fmt::Result {
+	replace_chars(out, s, |c| match c {
+		'\'' => Some("'\\''"),
+		_ => None,
+	})
+}
+
+/// Escapes the content of a quoted csv field.
+pub fn write_csv(out: &mut dyn fmt::Write, s: &str) -> fmt::Result {
+	replace_chars(out, s, |c| match c {
+		'"' => Some("\"\""),
+		_ => None,
+	})
+}
+
+/// Escapes the content of a rust string literal.
+pub fn write_rust(out: &mut dyn fmt::Write, s: &str) -> fmt::Result {
+	for c in s.chars() {
+		match c {
+			'"' => out.write_str("\\\"")?,
+			'\\' => out.write_str("\\\\")?,
+			'\n' => out.write_str("\\n")?,
+			'\r' => out.write_str("\\r")?,
+			'\t' => out.write_str("\\t")?,
+			'\0' => out.write_str("\\0")?,
+			c if c.is_control() => write!(out, "\\u{{{:x}}}", c as u32)?,
+			c => out.write_char(c)?,
+		}
+	}
+	Ok(())
+}
+
+/// Escapes `&`, `<`, `>`, `"` and `'` for html text and attribute values.
+pub fn html(s: &str) -> String { to_string(s, write_html) }
+
+/// Escapes `&`, `<`, `>`, `"` and `'` for xml text and attribute values.
+pub fn xml(s: &str) -> String { to_string(s, write_xml) }
+
+/// Escapes the content of a json string.
+pub fn json(s: &str) -> String { to_string(s, write_json) }
+
+/// Percent-encodes everything except unreserved characters
+/// (`A-Z a-z 0-9 - _ . ~`).
+pub fn url(s: &str) -> String { to_string(s, write_url) }
+
+/// Escapes the content of a single-quoted shell string.
+pub fn shell(s: &str) -> String { to_string(s, write_shell) }
+
+/// Escapes the content of a quoted csv field.
+pub fn csv(s: &str) -> String { to_string(s, write_csv) }
+
+/// Escapes the content of a rust string literal.
+pub fn rust(s: &str) -> String { to_string(s, write_rust) }
+
+fn to_string(
+	s: &str,
+	escape: fn(&mut dyn fmt::Write, &str) -> fmt::Result,
+) -> String
+{
+	let mut result = String::with_capacity(s.len());
+	escape(&mut result, s).expect("writing to a String cannot fail");
+	result
+}
+
+fn replace_chars(
+	out: &mut dyn fmt::Write,
+	s: &str,
+	replace: impl Fn(char) -> Option<&'static str>,
+) -> fmt::Result
+{
+	// Write unchanged parts in one go
+	let mut last = 0;
+	for (i, c) in s.char_indices() {
+		if let Some(r) = replace(c) {
+			out.write_str(&s[last..i])?;
+			out.write_str(r)?;
+			last = i + c.len_utf8();
+		}
+	}
+	out.write_str(&s[last..])
+}
@@ -0,0 +1,94 @@
+//! Runtime support for [t4rust](https://github.com/ReSpeak/t4rust) templates.
+//!
+//! Types deriving [`Template`](derive@Template) implement [`Display`] and the
+//! [`Template`] trait, which can render the template directly into any
+//! [`fmt::Write`] or [`io::Write`] without building a `String` first.
+//!
+//! ```
+//! use t4rust::Template;
+//!
+//! #[derive(Template)]
+//! #[TemplateSource = "Hello <#= self.name #>!"]
+//! struct Greeting {
+//!     name: String,
+//! }
+//!
+//! let greeting = Greeting { name: "Splamy".into() };
+//!
+//! let mut out = Vec::new();
+//! greeting.write_into(&mut out).unwrap();
+//! assert_eq!(out, b"Hello Splamy!");
+//! assert_eq!(greeting.render(), "Hello Splamy!");
+//! ```
+
+use std::fmt::{self, Display};
+use std::io;
+
+pub use t4rust_derive::RuntimeTemplate as Template;
+
+pub mod escape;
+
+/// Passes everything written to it through an escape function.
+///
+/// This is used for the `escape` directive with the `writer` or `mode`
+/// parameter, so `<#= expr #>` can be escaped while it is formatted, without
+/// allocating a `String` for every expression.
+pub struct EscapeWriter<'a, F> {
+	inner: &'a mut dyn fmt::Write,
+	escape: F,
+}
+
+impl<'a, F> EscapeWriter<'a, F>
+where F: FnMut(&mut dyn fmt::Write, &str) -> fmt::Result
+{
+	pub fn new(inner: &'a mut dyn fmt::Write, escape: F) -> Self {
+		Self { inner, escape }
+	}
+
+	/// Allows `write!` without importing `fmt::Write`.
+	pub fn write_fmt(&mut self, args: fmt::Arguments) -> fmt::Result {
+		fmt::Write::write_fmt(self, args)
+	}
+}
+
+impl<F> fmt::Write for EscapeWriter<'_, F>
+where F: FnMut(&mut dyn fmt::Write, &str) -> fmt::Result
+{
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		(self.escape)(&mut *self.inner, s)
+	}
+}
+
+/// A rendered template, implemented by `#[derive(Template)]`.
+pub trait Template: Display {
+	/// The length of the static text in the template.
+	///
+	/// Text in loops is only counted once, so the rendered template can be
+	/// longer or, if parts are skipped, shorter.
+	const SIZE_HINT: usize = 0;
+
+	/// Writes the template into a [`fmt::Write`], e.g. a `String`.
+	fn render_into<W: fmt::Write + ?Sized>(
+		&self,
+		writer: &mut W,
+	) -> fmt::Result {
+		write!(writer, "{}", self)
+	}
+
+	/// Writes the template into an [`io::Write`], e.g. a file or socket.
+	fn write_into<W: io::Write + ?Sized>(
+		&self,
+		writer: &mut W,
+	) -> io::Result<()> {
+		write!(writer, "{}", self)
+	}
+
+	/// Renders the template into a new `String`, which is preallocated with
+	/// [`SIZE_HINT`](Template::SIZE_HINT).
+	fn render(&self) -> String {
+		let mut result = String::with_capacity(Self::SIZE_HINT);
+		self.render_into(&mut result)
+			.expect("a Display implementation returned an error unexpectedly");
+		result
+	}
+}
@@ -0,0 +1,37 @@
+use t4rust_derive::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/bind_fields.tt"]
+#[TemplateBindFields]
+struct BindFields {
+	name: String,
+	food: &'static str,
+	num: u32,
+	#[allow(dead_code)]
+	unused: bool,
+}
+
+#[test]
+fn bind_fields() {
+	let f = format!(
+		"{}",
+		BindFields { name: "t4".into(), food: "cake", num: 2, unused: true }
+	);
+	assert_eq!(f, "t4 likes cake\nyum\nyum\n");
+}
+
+#[derive(Template)]
+#[TemplateSource = "<#= _0 #>-<#= _1 #>-<#= self.0 #>"]
+#[TemplateBindFields]
+struct BindTuple(u8, char);
+
+#[derive(Template)]
+#[TemplateSource = "unit"]
+#[TemplateBindFields]
+struct BindUnit;
+
+#[test]
+fn bind_tuple_and_unit() {
+	assert_eq!(format!("{}", BindTuple(1, 'a')), "1-a-1");
+	assert_eq!(format!("{}", BindUnit), "unit");
+}
@@ -0,0 +1,11 @@
+use t4rust_derive::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/comment.tt"]
+struct Comment;
+
+#[test]
+pub fn comment() {
+	let f = format!("{}", Comment);
+	assert_eq!(f, "text\ntext2 end\ntext3\n");
+}
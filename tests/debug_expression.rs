@@ -0,0 +1,24 @@
+use t4rust::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/debug_expression.tt"]
+struct DebugExpression {
+	items: Vec<u8>,
+	name: &'static str,
+}
+
+#[test]
+fn debug_expression() {
+	let f = format!("{}", DebugExpression { items: vec![1, 2], name: "<t4>" });
+	let f = f.trim_end_matches(['\r', '\n']);
+
+	assert_eq!(
+		f,
+		"[1, 2] \"<t4>\"
+[
+    1,
+    2,
+]
+&quot;&lt;t4&gt;&quot;"
+	);
+}
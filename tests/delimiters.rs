@@ -0,0 +1,28 @@
+use t4rust_derive::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/delimiters.tt"]
+#[TemplateDelimiters = "{% %}"]
+struct Delimiters {
+	count: i32,
+}
+
+#[derive(Template)]
+#[TemplateSource = "<#= T4 #> <%= self.0 %>"]
+#[TemplateDelimiters = "<% %>"]
+struct InlineDelimiters(&'static str);
+
+#[test]
+pub fn delimiters() {
+	let f = format!("{}", Delimiters { count: 3 });
+	assert_eq!(
+		f,
+		"<# This T4 code is just text #>\n012\nLiteral delimiters: {% and %}\n"
+	);
+}
+
+#[test]
+pub fn inline_delimiters() {
+	let f = format!("{}", InlineDelimiters("text"));
+	assert_eq!(f, "<#= T4 #> text");
+}
@@ -0,0 +1,50 @@
+use t4rust_derive::Template;
+
+#[derive(Template)]
+enum Event<'a> {
+	#[TemplatePath = "./tests/enum_variants/message.tt"]
+	Message { author: &'a str, text: &'a str },
+	#[TemplateSource = "<#= _0 #> joined as <#= _1 #>\n"]
+	Join(&'a str, u32),
+	#[TemplateSource = "Goodbye\n"]
+	Quit,
+	#[TemplateDelimiters = "{% %}"]
+	#[TemplateSource = "<# {%= name %} #>\n"]
+	Raw { name: &'a str },
+}
+
+#[test]
+fn enum_variants() {
+	let events = [
+		Event::Join("t4", 1),
+		Event::Message { author: "t4", text: "Hello\nWorld" },
+		Event::Raw { name: "code" },
+		Event::Quit,
+	];
+	let f: String = events.iter().map(|e| e.to_string()).collect();
+
+	assert_eq!(
+		f,
+		"t4 joined as 1
+t4: Hello
+t4: World
+<# code #>
+Goodbye
+"
+	);
+}
+
+#[derive(Template)]
+#[TemplateSource = "<# match self { #>\
+	<# Self::On => { #>on<# } #>\
+	<# Self::Off => { #>off<# } #>\
+	<# } #>"]
+enum Switch {
+	On,
+	Off,
+}
+
+#[test]
+fn enum_single_template() {
+	assert_eq!(format!("{} {}", Switch::On, Switch::Off), "on off");
+}
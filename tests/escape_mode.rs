@@ -0,0 +1,37 @@
+use t4rust::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/escape_mode.tt"]
+struct EscapeMode {
+	text: String,
+}
+
+#[test]
+fn escape_mode() {
+	let f = EscapeMode { text: r#"<a href="x">It's "1 & 2"</a>"#.into() }.render();
+	assert_eq!(
+		f,
+		r#"
+&lt;a href=&quot;x&quot;&gt;It&#x27;s &quot;1 &amp; 2&quot;&lt;/a&gt;
+
+"<a href=\"x\">It's \"1 & 2\"</a>"
+
+?q=%3Ca%20href%3D%22x%22%3EIt%27s%20%221%20%26%202%22%3C%2Fa%3E
+
+echo '<a href="x">It'\''s "1 & 2"</a>'
+
+<a href="x">It's "1 & 2"</a>
+"#
+	);
+}
+
+#[test]
+fn escape_functions() {
+	use t4rust::escape;
+
+	assert_eq!(escape::xml("<'&'>"), "&lt;&apos;&amp;&apos;&gt;");
+	assert_eq!(escape::json("a\"\\\n\u{1}"), "a\\\"\\\\\\n\\u0001");
+	assert_eq!(escape::url("ä/b"), "%C3%A4%2Fb");
+	assert_eq!(escape::csv("a,\"b\""), "a,\"\"b\"\"");
+	assert_eq!(escape::rust("\"a\"\n"), "\\\"a\\\"\\n");
+}
@@ -0,0 +1,23 @@
+use std::fmt;
+
+use t4rust::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/escape_writer.tt"]
+struct EscapeWriter {
+	text: String,
+	num: i32,
+}
+
+fn shout(out: &mut dyn fmt::Write, s: &str) -> fmt::Result {
+	for c in s.chars() {
+		out.write_char(c.to_ascii_uppercase())?;
+	}
+	Ok(())
+}
+
+#[test]
+fn escape_writer() {
+	let f = EscapeWriter { text: "quiet".into(), num: -5 }.render();
+	assert_eq!(f, "\nQUIET -5\n\nquiet\n");
+}
@@ -0,0 +1,75 @@
+use t4rust_derive::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/extends/page.tt"]
+struct Page {
+	title: &'static str,
+}
+
+#[test]
+fn extends() {
+	let f = format!("{}", Page { title: "Hello" });
+	assert_eq!(
+		f,
+		"<html>
+<head><title>Hello</title></head>
+<body>
+<h1>Hello</h1>
+<p>Footer</p>
+</body>
+</html>
+"
+	);
+}
+
+#[derive(Template)]
+#[TemplatePath = "./tests/extends/plain.tt"]
+struct Plain {
+	title: &'static str,
+}
+
+#[test]
+fn extends_chain() {
+	let f = format!("{}", Plain { title: "Hello" });
+	assert_eq!(
+		f,
+		"<html>
+<head><title>Hello</title></head>
+<body>
+Plain Hello
+</body>
+</html>
+"
+	);
+}
+
+#[derive(Template)]
+#[TemplatePath = "./tests/extends/base.tt"]
+struct Base;
+
+#[test]
+fn extends_base() {
+	let f = format!("{}", Base);
+	assert_eq!(
+		f,
+		"<html>
+<head><title>Default</title></head>
+<body>
+No content
+<p>Footer</p>
+</body>
+</html>
+"
+	);
+}
+
+#[derive(Template)]
+#[TemplatePath = "./tests/extends/nested.tt"]
+struct Nested;
+
+#[test]
+fn extends_nested_override() {
+	// `inner` is part of the replaced `outer` block, so its override is unused
+	let f = format!("{}", Nested);
+	assert_eq!(f, "[Outer]\n");
+}
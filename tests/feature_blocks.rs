@@ -0,0 +1,31 @@
+use t4rust_derive::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/feature_blocks.tt"]
+struct FeatureBlocks {
+	people: Vec<&'static str>,
+}
+
+#[test]
+fn feature_blocks() {
+	let f = format!("{}", FeatureBlocks { people: vec!["Alice", "Bob"] });
+	assert_eq!(f, "Names: Alice, Bob\nA.\nB.\n");
+	assert_eq!(FeatureBlocks::SEPARATOR, ", ");
+}
+
+// Both templates include the same feature blocks
+#[derive(Template)]
+#[TemplateSource = r#"<#@ include file="tests/feature_blocks/helpers.tt" #>
+<#= Tag("p") #><#= self.helper() #>"#]
+#[TemplateNamed(name = "short", source = r#"<#@ include file="tests/feature_blocks/helpers.tt" #>
+<#= self.helper() #>"#)]
+struct Shared {
+	name: &'static str,
+}
+
+#[test]
+fn shared_feature_blocks() {
+	let shared = Shared { name: "a" };
+	assert_eq!(shared.to_string(), "\n\n<p>A");
+	assert_eq!(shared.render_short(), "\n\nA");
+}
@@ -0,0 +1,34 @@
+use std::collections::HashMap;
+use t4rust::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/format_spec.tt"]
+struct FormatSpec {
+	price: f64,
+	id: u32,
+	name: &'static str,
+	lookup: HashMap<u32, i32>,
+}
+
+#[test]
+pub fn format_spec() {
+	let mut lookup = HashMap::new();
+	lookup.insert(0, 5);
+	let f = format!(
+		"{}",
+		FormatSpec { price: 12.3456, id: 255, name: "t4", lookup }
+	);
+	assert_eq!(
+		f,
+		"[     12.35]\n[0xff] [11111111]\n[***t4****] [t4    ]\n[  a:b]\n[+5]\n"
+	);
+}
+
+#[derive(Template)]
+#[TemplateSource = "<#@ escape mode=\"html\" #>[<#= self.0 :>6 #>]"]
+struct FormatSpecEscaped(&'static str);
+
+#[test]
+pub fn format_spec_escaped() {
+	assert_eq!(format!("{}", FormatSpecEscaped("<b>")), "[   &lt;b&gt;]");
+}
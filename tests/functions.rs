@@ -0,0 +1,53 @@
+use t4rust::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/functions.tt"]
+struct Functions<'a> {
+	items: Vec<(&'a str, u32)>,
+}
+
+#[test]
+fn functions() {
+	let f = format!("{}", Functions { items: vec![("a", 2), ("b", 3)] });
+	let f = f.trim_end_matches(['\r', '\n']);
+	assert_eq!(
+		f,
+		"<ul>
+<li>a x2</li>
+<li>b x3</li>
+</ul>
+<li>&lt;extra&gt; x1</li>
+End"
+	);
+}
+
+// Owned parameters are borrowed in the body, so the function can be rendered
+// more than once
+#[derive(Template)]
+#[TemplateSource = r##"<#@ function name="sum" params="values: Vec<u32>, (label, sep): (String, char)" #>
+<#= label #>:<# for v in values { #><#= sep #><#= v #><# } #><#@ endfunction #>"##]
+struct Owned;
+
+#[test]
+fn owned_parameters() {
+	let sum = Owned.sum(vec![1, 2], ("Sum".into(), ' '));
+	assert_eq!(sum.to_string(), "\nSum: 1 2");
+	assert_eq!(sum.to_string(), "\nSum: 1 2");
+}
+
+// Both templates include the same functions
+#[derive(Template)]
+#[TemplateSource = r#"<#@ include file="tests/functions/macros.tt" #>
+<#! self.item(self.name) #>"#]
+#[TemplateNamed(name = "html", source = r#"<#@ include file="tests/functions/macros.tt" #>
+<b><#! self.item(self.name) #></b>"#)]
+struct Shared {
+	name: &'static str,
+}
+
+#[test]
+fn shared_functions() {
+	let shared = Shared { name: "a" };
+	assert_eq!(shared.to_string(), "\n\n[a]");
+	assert_eq!(shared.render_html(), "\n\n<b>[a]</b>");
+}
@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+use t4rust_derive::Template;
+
+mod helpers {
+	pub fn shout(s: &str) -> String { s.to_uppercase() }
+	pub fn double(v: u32) -> u32 { v * 2 }
+}
+
+#[derive(Template)]
+#[TemplatePath = "./tests/import.tt"]
+struct Import {
+	values: HashMap<&'static str, u32>,
+}
+
+#[test]
+fn import() {
+	let values = vec![("b", 2), ("a", 1)].into_iter().collect();
+	let f = format!("{}", Import { values });
+	assert_eq!(f, "A=2\nB=4\n");
+}
+
+// Every template has its own imports, so they can import the same names
+#[derive(Template)]
+#[TemplateSource = "<#@ import namespace=\"std::fmt::Write\" #>\
+	<# let mut s = String::new(); write!(s, \"{}\", self.0)?; #><#= s #>"]
+#[TemplateNamed(
+	name = "twice",
+	source = "<#@ import namespace=\"std::fmt::Write\" #>\
+		<# let mut s = String::new(); write!(s, \"{0}{0}\", self.0)?; #><#= s #>"
+)]
+struct Shared(u32);
+
+#[test]
+fn same_import_in_two_templates() {
+	assert_eq!(Shared(4).to_string(), "4");
+	assert_eq!(Shared(4).render_twice(), "44");
+}
@@ -0,0 +1,23 @@
+use t4rust_derive::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/include.tt"]
+struct Include {
+	title: String,
+}
+
+#[derive(Template)]
+#[TemplateSource = "Inline <#@ include file=\"tests/include/line.tt\" #>"]
+struct InlineInclude;
+
+#[test]
+pub fn include() {
+	let f = format!("{}", Include { title: "Page".into() });
+	assert_eq!(f, "Header for Page\nBody [line][line]\nFooter\n");
+}
+
+#[test]
+pub fn inline_include() {
+	let f = format!("{}", InlineInclude);
+	assert_eq!(f, "Inline [line]");
+}
@@ -0,0 +1,11 @@
+use t4rust_derive::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/lenient.tt"]
+struct Lenient;
+
+#[test]
+pub fn lenient() {
+	let f = format!("{}", Lenient);
+	assert_eq!(f, "Still clean\n");
+}
@@ -0,0 +1,43 @@
+use std::fmt::Write;
+use t4rust::Template;
+
+#[derive(Template)]
+#[TemplateSource = "<# for item in self.items { #>- <#= item #>\n<# } #>"]
+#[TemplateNamed(name = "html", path = "./tests/named_templates/html.tt")]
+#[TemplateNamed(name = "csv", source = "<#= self.items.join(\",\") #>")]
+struct List<'a> {
+	items: &'a [&'a str],
+}
+
+#[test]
+fn named_templates() {
+	let list = List { items: &["a", "<b>"] };
+
+	assert_eq!(list.to_string(), "- a\n- <b>\n");
+	assert_eq!(
+		list.render_html(),
+		"<ul>\n\t<li>a</li>\n\t<li>&lt;b&gt;</li>\n</ul>\n"
+	);
+
+	let mut s = String::from("items: ");
+	list.render_csv_into(&mut s).unwrap();
+	assert_eq!(s, "items: a,<b>");
+}
+
+/// Only has named templates, so it does not implement `Display`
+#[derive(Template)]
+#[TemplateNamed(name = "short", source = "<#= self.value #>")]
+#[TemplateNamed(name = "long", source = "Value: <#= value #>")]
+#[TemplateBindFields]
+struct Value<T: std::fmt::Display> {
+	value: T,
+}
+
+#[test]
+fn named_templates_only() {
+	let value = Value { value: 5 };
+	let mut s = String::new();
+	write!(s, "{} ", value.render_short()).unwrap();
+	value.render_long_into(&mut s).unwrap();
+	assert_eq!(s, "5 Value: 5");
+}
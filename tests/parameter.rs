@@ -0,0 +1,32 @@
+use t4rust_derive::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/parameter.tt"]
+struct Parameter {
+	title: String,
+	count: u32,
+	tags: Vec<&'static str>,
+}
+
+#[test]
+fn parameter() {
+	let f = format!(
+		"{}",
+		Parameter { title: "Post".into(), count: 2, tags: vec!["a", "b"] }
+	);
+	assert_eq!(f, "Post (2): a, b\n");
+}
+
+#[derive(Template)]
+enum Shape {
+	#[TemplateSource = "<#@ parameter name=\"0\" type=\"f32\" #><#= _0 #>"]
+	Circle(f32),
+	#[TemplateSource = "<#@ parameter name=\"_1\" #><#= _0 #>x<#= _1 #>"]
+	Rectangle(u8, u8),
+}
+
+#[test]
+fn parameter_variants() {
+	assert_eq!(Shape::Circle(1.5).to_string(), "1.5");
+	assert_eq!(Shape::Rectangle(2, 3).to_string(), "2x3");
+}
@@ -0,0 +1,22 @@
+use t4rust_derive::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/raw_expression.tt"]
+struct RawExpression {
+	markup: String,
+}
+
+fn escape_html(s: &str) -> String { s.replace('<', "&lt;").replace('>', "&gt;") }
+
+#[test]
+fn raw_expression() {
+	let f = format!("{}", RawExpression { markup: "<b>bold</b>".into() });
+	let f = f.trim_end_matches(['\r', '\n']);
+
+	assert_eq!(
+		f,
+		"Escaped: &lt;b&gt;bold&lt;/b&gt;
+Raw: <b>bold</b>
+Escaped again: &lt;b&gt;bold&lt;/b&gt;"
+	);
+}
@@ -0,0 +1,27 @@
+use t4rust_derive::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/raw_region.tt"]
+struct RawRegion {
+	name: String,
+}
+
+#[test]
+pub fn raw_region() {
+	let f = format!("{}", RawRegion { name: "t4".into() });
+	assert_eq!(
+		f,
+		"Before t4\nUse <#= self.name #> to print a value, \"#\" and <#@ \
+		 template #> stay.\nAfter <# inline #> done\n"
+	);
+}
+
+#[derive(Template)]
+#[TemplateDelimiters = "{% %}"]
+#[TemplateSource = "{%@ raw -%}  {% code %} <# #> {%-@ endraw %}!"]
+struct RawRegionDelimiters;
+
+#[test]
+pub fn raw_region_delimiters() {
+	assert_eq!(format!("{}", RawRegionDelimiters), "{% code %} <# #>!");
+}
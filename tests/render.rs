@@ -0,0 +1,35 @@
+use t4rust::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/simple_template.tt"]
+struct Render {
+	text: String,
+}
+
+#[test]
+pub fn render() {
+	let f = Render { text: "Inner".into() }.render();
+	assert_eq!(f.trim_end_matches(['\r', '\n']), "Text Inner Other Text");
+}
+
+#[test]
+pub fn render_into() {
+	let mut f = String::from("Before: ");
+	Render { text: "Inner".into() }.render_into(&mut f).unwrap();
+	assert_eq!(f.trim_end_matches(['\r', '\n']), "Before: Text Inner Other Text");
+}
+
+#[test]
+pub fn write_into() {
+	let mut f = Vec::new();
+	Render { text: "Inner".into() }.write_into(&mut f).unwrap();
+	let f = String::from_utf8(f).unwrap();
+	assert_eq!(f.trim_end_matches(['\r', '\n']), "Text Inner Other Text");
+}
+
+#[test]
+pub fn size_hint() {
+	// "Text " and " Other Text" plus the newline at the end of the file
+	assert_eq!(Render::SIZE_HINT, 17);
+	assert!(Render { text: "".into() }.render().capacity() >= Render::SIZE_HINT);
+}
@@ -0,0 +1,40 @@
+use t4rust_derive::Template;
+
+#[derive(Template)]
+#[TemplateSource = "Text <#= self.text #> Other Text"]
+struct InlineTemplate {
+	text: String,
+}
+
+#[derive(Template)]
+#[TemplateSource = r#"<#@ template cleanws="true" #>
+Items:
+<# for item in &self.items { #>
+ - "<#= item #>"
+<# } #>
+"#]
+struct InlineRawTemplate {
+	items: Vec<&'static str>,
+}
+
+#[test]
+pub fn inline_template() {
+	let f = format!("{}", InlineTemplate { text: "Inner".into() });
+	assert_eq!(f, "Text Inner Other Text");
+}
+
+#[test]
+pub fn inline_raw_template() {
+	let f = format!("{}", InlineRawTemplate { items: vec!["a", "b"] });
+	assert_eq!(f, "Items:\n - \"a\"\n - \"b\"\n");
+}
+
+#[derive(Template)]
+#[TemplateSource = "a<# // comment #>b"]
+struct LineComment;
+
+#[test]
+pub fn line_comment() {
+	let f = format!("{}", LineComment);
+	assert_eq!(f, "ab");
+}
@@ -0,0 +1,13 @@
+use t4rust_derive::Template;
+
+#[derive(Template)]
+#[TemplatePath = "./tests/trim_markers.tt"]
+struct TrimMarkers {
+	items: Vec<i32>,
+}
+
+#[test]
+pub fn trim_markers() {
+	let f = format!("{}", TrimMarkers { items: vec![1, 2, 3] });
+	assert_eq!(f, "List:1,2,3,\nDone\nCompact: [3]\n[line]End\n");
+}